@@ -0,0 +1,227 @@
+//! The block module manages marked blocks of text inside target files that are otherwise owned by the user or other
+//! tools. A block starts with a `# BEGIN dotpatina <name>` line and ends with a `# END dotpatina <name>` line.
+
+use std::ops::Range;
+use std::path::Path;
+
+use crate::utils::{Error, Result};
+
+/// The line that starts a managed block
+fn begin_marker(name: &str) -> String {
+    format!("# BEGIN dotpatina {}", name)
+}
+
+/// The line that ends a managed block
+fn end_marker(name: &str) -> String {
+    format!("# END dotpatina {}", name)
+}
+
+/// The byte ranges of a managed block within a file
+struct BlockRanges {
+    /// The range of the whole block, including the marker lines
+    outer: Range<usize>,
+
+    /// The range of the text between the marker lines
+    inner: Range<usize>,
+}
+
+/// Find the managed block with the given name in the contents of the file at `path`.
+/// Returns an error if the markers are missing, duplicated, or out of order.
+fn find_block(path: &Path, contents: &str, name: &str) -> Result<Option<BlockRanges>> {
+    let begin_marker = begin_marker(name);
+    let end_marker = end_marker(name);
+    let invalid_block = |message: String| Err(Error::InvalidBlock(path.to_path_buf(), message));
+
+    let mut begin: Option<Range<usize>> = None;
+    let mut block: Option<BlockRanges> = None;
+    let mut offset = 0;
+    for line in contents.split_inclusive('\n') {
+        let line_range = offset..offset + line.len();
+        offset += line.len();
+
+        let line = line.trim_end();
+        if line == begin_marker {
+            if begin.is_some() || block.is_some() {
+                return invalid_block(format!("found multiple `{}` markers", begin_marker));
+            }
+            begin = Some(line_range);
+        } else if line == end_marker {
+            let Some(begin) = begin.take() else {
+                return invalid_block(format!("found `{}` without `{}`", end_marker, begin_marker));
+            };
+            block = Some(BlockRanges {
+                outer: begin.start..line_range.end,
+                inner: begin.end..line_range.start,
+            });
+        }
+    }
+
+    if begin.is_some() {
+        return invalid_block(format!("found `{}` without `{}`", begin_marker, end_marker));
+    }
+    Ok(block)
+}
+
+/// Get the text of the managed block with the given name, if the block exists
+pub fn get_block<'c>(path: &Path, contents: &'c str, name: &str) -> Result<Option<&'c str>> {
+    let block = find_block(path, contents, name)?;
+    Ok(block.map(|block| &contents[block.inner]))
+}
+
+/// Replace the text of the managed block with the given name, leaving the rest of the contents alone.
+/// If the block does not exist yet, it is appended to the end of the contents.
+pub fn replace_block(path: &Path, contents: &str, name: &str, text: &str) -> Result<String> {
+    let mut block_str = format!("{}\n{}", begin_marker(name), text);
+    if !block_str.ends_with('\n') {
+        block_str.push('\n');
+    }
+    block_str.push_str(&end_marker(name));
+    block_str.push('\n');
+
+    let result = match find_block(path, contents, name)? {
+        Some(block) => {
+            let mut result = String::from(&contents[..block.outer.start]);
+            result.push_str(&block_str);
+            result.push_str(&contents[block.outer.end..]);
+            result
+        }
+        None if contents.is_empty() => block_str,
+        None => {
+            let mut result = String::from(contents);
+            if !result.ends_with('\n') {
+                result.push('\n');
+            }
+            result.push('\n');
+            result.push_str(&block_str);
+            result
+        }
+    };
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_block() {
+        let contents = r#"export PATH=$PATH:~/bin
+# BEGIN dotpatina shell
+alias ll="ls -l"
+# END dotpatina shell
+export EDITOR=vim
+"#;
+
+        let block = get_block(Path::new(".bashrc"), contents, "shell");
+
+        assert!(block.is_ok());
+        assert_eq!(block.unwrap(), Some("alias ll=\"ls -l\"\n"));
+    }
+
+    #[test]
+    fn test_get_block_missing() {
+        let block = get_block(Path::new(".bashrc"), "export EDITOR=vim\n", "shell");
+
+        assert!(block.is_ok());
+        assert_eq!(block.unwrap(), None);
+    }
+
+    #[test]
+    fn test_get_block_other_name() {
+        let contents = "# BEGIN dotpatina other\nalias ll=\"ls -l\"\n# END dotpatina other\n";
+
+        let block = get_block(Path::new(".bashrc"), contents, "shell");
+
+        assert!(block.is_ok());
+        assert_eq!(block.unwrap(), None);
+    }
+
+    #[test]
+    fn test_get_block_invalid_markers() {
+        let cases = [
+            (
+                "# BEGIN dotpatina shell\nalias ll=\"ls -l\"\n",
+                "found `# BEGIN dotpatina shell` without `# END dotpatina shell`",
+            ),
+            (
+                "alias ll=\"ls -l\"\n# END dotpatina shell\n",
+                "found `# END dotpatina shell` without `# BEGIN dotpatina shell`",
+            ),
+            (
+                "# BEGIN dotpatina shell\n# END dotpatina shell\n# BEGIN dotpatina shell\n# END dotpatina shell\n",
+                "found multiple `# BEGIN dotpatina shell` markers",
+            ),
+        ];
+
+        for (contents, message) in cases {
+            let block = get_block(Path::new(".bashrc"), contents, "shell");
+
+            assert!(block.is_err());
+            assert_eq!(
+                block.unwrap_err().as_invalid_block(),
+                Some((&Path::new(".bashrc").to_path_buf(), &message.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_replace_block() {
+        let contents = r#"export PATH=$PATH:~/bin
+# BEGIN dotpatina shell
+alias ll="ls -l"
+# END dotpatina shell
+export EDITOR=vim
+"#;
+
+        let result = replace_block(
+            Path::new(".bashrc"),
+            contents,
+            "shell",
+            "alias la=\"ls -a\"",
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            r#"export PATH=$PATH:~/bin
+# BEGIN dotpatina shell
+alias la="ls -a"
+# END dotpatina shell
+export EDITOR=vim
+"#
+        );
+    }
+
+    #[test]
+    fn test_replace_block_appends_missing_block() {
+        let result = replace_block(
+            Path::new(".bashrc"),
+            "export EDITOR=vim",
+            "shell",
+            "alias ll=\"ls -l\"\n",
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            r#"export EDITOR=vim
+
+# BEGIN dotpatina shell
+alias ll="ls -l"
+# END dotpatina shell
+"#
+        );
+    }
+
+    #[test]
+    fn test_replace_block_empty_contents() {
+        let result = replace_block(Path::new(".bashrc"), "", "shell", "alias ll=\"ls -l\"\n");
+
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            "# BEGIN dotpatina shell\nalias ll=\"ls -l\"\n# END dotpatina shell\n"
+        );
+    }
+}
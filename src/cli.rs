@@ -9,6 +9,10 @@ use std::io::Write;
 use std::path::PathBuf;
 
 use crate::engine::{interface::PatinaInterface, PatinaEngine};
+use crate::patina::secrets::{self, SecretKey};
+use crate::patina::{tags::TagExpression, Patina};
+use crate::utils::{Error, Result};
+use age::secrecy::SecretString;
 use clap::{Args, Parser, Subcommand};
 use log::info;
 
@@ -59,6 +63,88 @@ enum Command {
         #[clap(long = "no-trash")]
         no_trash: bool,
     },
+
+    /// Check a patina for problems without applying it
+    #[clap(about = "Check a patina for problems without applying it")]
+    Validate {
+        /// Command line options
+        #[clap(flatten)]
+        options: PatinaCommandOptions,
+    },
+
+    /// Migrate a patina file to the latest schema version
+    #[clap(about = "Migrate a patina file to the latest schema version")]
+    Migrate {
+        /// Included global options
+        #[clap(flatten)]
+        global_options: GlobalOptions,
+
+        /// The file path to the patina toml file
+        patina_path: PathBuf,
+    },
+
+    /// Manage encrypted variables files
+    #[clap(about = "Manage encrypted variables files")]
+    Vars {
+        /// The vars command to run
+        #[clap(subcommand)]
+        command: VarsCommand,
+    },
+}
+
+/// The available commands for managing encrypted variables files
+#[derive(Debug, Subcommand)]
+enum VarsCommand {
+    /// Encrypt a variables file
+    #[clap(about = "Encrypt a variables file")]
+    Encrypt {
+        /// Options for the encryption key
+        #[clap(flatten)]
+        secret_key_options: SecretKeyOptions,
+
+        /// The plaintext variables file to encrypt
+        path: PathBuf,
+
+        /// The path of the encrypted file, which defaults to the plaintext path with an `.age` extension added
+        #[clap(short = 'o', long = "output")]
+        output: Option<PathBuf>,
+    },
+
+    /// Decrypt a variables file to stdout
+    #[clap(about = "Decrypt a variables file to stdout")]
+    Decrypt {
+        /// Options for the encryption key
+        #[clap(flatten)]
+        secret_key_options: SecretKeyOptions,
+
+        /// The encrypted variables file to decrypt
+        path: PathBuf,
+    },
+
+    /// Edit an encrypted variables file with $VISUAL or $EDITOR
+    #[clap(about = "Edit an encrypted variables file with $VISUAL or $EDITOR")]
+    Edit {
+        /// Options for the encryption key
+        #[clap(flatten)]
+        secret_key_options: SecretKeyOptions,
+
+        /// The encrypted variables file to edit, which is created if it does not exist
+        path: PathBuf,
+    },
+}
+
+/// Options for the key of encrypted variables files.
+/// Without an identity file, the passphrase is read from `DOTPATINA_PASSPHRASE` or asked for.
+#[derive(Debug, Args)]
+struct SecretKeyOptions {
+    /// An age identity file for encrypted variables files
+    #[clap(
+        short = 'i',
+        long = "identity",
+        env = "DOTPATINA_IDENTITY",
+        help = "An age identity file for encrypted variables files"
+    )]
+    identity: Option<PathBuf>,
 }
 
 /// Options that apply to patina subcommands
@@ -75,13 +161,46 @@ struct PatinaCommandOptions {
     #[clap(long = "no-color")]
     no_color: bool,
 
-    /// The list of tags to filter on
-    #[clap(short = 't', long = "tags", help = "A set of tags to filter on")]
+    /// The list of tag expressions to filter on
+    #[clap(
+        short = 't',
+        long = "tags",
+        help = "A set of tags or tag expressions to filter on, such as 'shell & !work'"
+    )]
     tags: Vec<String>,
 
+    /// The list of tag expressions to exclude
+    #[clap(
+        short = 'x',
+        long = "exclude-tags",
+        help = "A set of tags or tag expressions to exclude"
+    )]
+    exclude_tags: Vec<String>,
+
     /// A list of variables files
     #[clap(short = 'f', long = "vars", help = "A set of variables files")]
     variables_files: Vec<PathBuf>,
+
+    /// A list of variables to set
+    #[clap(
+        short = 's',
+        long = "set",
+        value_name = "KEY=VALUE",
+        help = "Set a variable, like me.email=x@y.com. Values are parsed as TOML, and null removes the variable"
+    )]
+    set_vars: Vec<String>,
+
+    /// The name of a profile in the patina, which provides variables files and tags
+    #[clap(
+        short = 'p',
+        long = "profile",
+        help = "A profile of variables files and tags to use"
+    )]
+    profile: Option<String>,
+
+    /// Options for the key of encrypted variables files
+    #[clap(flatten)]
+    secret_key_options: SecretKeyOptions,
 }
 
 impl PatinaCli {
@@ -98,19 +217,27 @@ impl PatinaCli {
 
         let mut pi = CliPatinaInterface::new();
         let result = match &self.command {
-            Command::Render { options } => options.engine(&pi).render_patina(),
+            Command::Render { options } => options.engine(&pi).and_then(|e| e.render_patina()),
             Command::Apply {
                 options,
                 no_input,
                 no_trash,
             } => {
                 pi.set_is_input_enabled(!*no_input);
-                options.engine(&pi).apply_patina(!*no_trash)
+                options.engine(&pi).and_then(|e| e.apply_patina(!*no_trash))
+            }
+            Command::Validate { options } => options.engine(&pi).and_then(|e| e.validate_patina()),
+            Command::Migrate { patina_path, .. } => {
+                PatinaEngine::new(&pi, patina_path, None, vec![]).migrate_patina()
             }
+            Command::Vars { command } => command.run(&pi),
         };
 
-        if let Err(e) = result {
-            panic!("{:?}", e)
+        match result {
+            Ok(()) => {}
+            // Validation problems have already been reported
+            Err(Error::InvalidPatina(_)) => std::process::exit(1),
+            Err(e) => panic!("{:?}", e),
         }
     }
 }
@@ -149,29 +276,118 @@ impl PatinaInterface for CliPatinaInterface {
 }
 
 impl PatinaCommandOptions {
-    fn engine<'a, PI>(&self, pi: &'a PI) -> PatinaEngine<'a, PI>
+    fn engine<'a, PI>(&self, pi: &'a PI) -> Result<PatinaEngine<'a, PI>>
     where
         PI: PatinaInterface,
     {
-        let engine = PatinaEngine::new(
-            pi,
-            &self.patina_path,
-            self.tags.clone(),
-            self.variables_files.clone(),
-        );
+        // A profile's variables files and tags come before the ones given explicitly,
+        // so that explicit variables files take precedence.
+        let mut tags = vec![];
+        let mut variables_files = vec![];
+        if let Some(profile) = &self.profile {
+            let patina = Patina::from_file(&self.patina_path)?;
+            let profile = patina.get_profile(profile)?;
+            tags.extend(profile.tags);
+            variables_files.extend(profile.vars_files);
+        }
+        tags.extend(self.tags.iter().cloned());
+        variables_files.extend(self.variables_files.iter().cloned());
+
+        let tags = TagExpression::from_args(&tags, &self.exclude_tags)?;
+
         info!(
             r#"New PatinaEngine
             path = {}
+            profile = {:?}
             tags = {:?}
         "#,
             self.patina_path.display(),
-            self.tags
+            self.profile,
+            tags
         );
+        let secret_key = match variables_files.iter().any(|f| secrets::is_encrypted(f)) {
+            true => Some(self.secret_key_options.secret_key(false)?),
+            false => None,
+        };
+        let engine = PatinaEngine::new(pi, &self.patina_path, tags, variables_files)
+            .with_secret_key(secret_key)
+            .with_set_vars(self.set_vars.clone());
 
         if self.no_color {
             colored::control::set_override(false);
         }
 
-        engine
+        Ok(engine)
+    }
+}
+
+impl VarsCommand {
+    /// Run the vars command
+    fn run<PI: PatinaInterface>(&self, pi: &PI) -> Result<()> {
+        match self {
+            VarsCommand::Encrypt {
+                secret_key_options,
+                path,
+                output,
+            } => {
+                let output = output.clone().unwrap_or_else(|| {
+                    let mut output = path.clone().into_os_string();
+                    output.push(".");
+                    output.push(secrets::AGE_EXTENSION);
+                    PathBuf::from(output)
+                });
+                let secret_key = secret_key_options.secret_key(true)?;
+                secrets::encrypt_file(path, &output, &secret_key)?;
+                pi.output(format!(
+                    "Encrypted {} to {}\nThe plaintext file was kept, remove it before committing\n",
+                    path.display(),
+                    output.display()
+                ));
+            }
+            VarsCommand::Decrypt {
+                secret_key_options,
+                path,
+            } => {
+                let secret_key = secret_key_options.secret_key(false)?;
+                pi.output(secrets::read_encrypted_file(path, &secret_key)?);
+            }
+            VarsCommand::Edit {
+                secret_key_options,
+                path,
+            } => {
+                let secret_key = secret_key_options.secret_key(!path.exists())?;
+                let editor = std::env::var("VISUAL")
+                    .or_else(|_| std::env::var("EDITOR"))
+                    .unwrap_or_else(|_| String::from("vi"));
+                match secrets::edit_encrypted_file(path, &secret_key, &editor)? {
+                    true => pi.output(format!("Saved {}\n", path.display())),
+                    false => pi.output(format!("No changes to {}\n", path.display())),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SecretKeyOptions {
+    /// Get the key for encrypted variables files, asking for a passphrase if there is no identity file.
+    /// When `confirm` is set, a passphrase that is asked for must be entered twice.
+    fn secret_key(&self, confirm: bool) -> Result<SecretKey> {
+        if let Some(identity) = &self.identity {
+            return Ok(SecretKey::Identity(identity.clone()));
+        }
+        if let Ok(passphrase) = std::env::var("DOTPATINA_PASSPHRASE") {
+            return Ok(SecretKey::Passphrase(SecretString::from(passphrase)));
+        }
+
+        let passphrase = rpassword::prompt_password("Passphrase: ").map_err(Error::GetUserInput)?;
+        if confirm {
+            let confirmation =
+                rpassword::prompt_password("Confirm passphrase: ").map_err(Error::GetUserInput)?;
+            if confirmation != passphrase {
+                return Err(Error::Message(String::from("passphrases do not match")));
+            }
+        }
+        Ok(SecretKey::Passphrase(SecretString::from(passphrase)))
     }
 }
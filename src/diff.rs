@@ -4,6 +4,7 @@
 use std::cmp::max;
 
 use colored::{Color, Colorize};
+use sha2::{Digest, Sha256};
 use similar::{Change, ChangeTag, TextDiff};
 
 /// [DiffAnalysis] provides functionality for diffs within dotpatina
@@ -15,6 +16,12 @@ pub trait DiffAnalysis {
     fn to_string(&self) -> String;
 }
 
+/// Summarize binary file contents by their size and hash, since they can't be shown as a line diff
+pub fn binary_summary(bytes: &[u8]) -> String {
+    let hash = format!("{:x}", Sha256::digest(bytes));
+    format!("{} bytes, sha256 {}", bytes.len(), &hash[..16])
+}
+
 /// Details for a line output for a diff
 struct DiffLine {
     /// The line number in the old file
@@ -463,4 +470,13 @@ mod tests {
         let expected_lines = ["3 unchanged lines", ""];
         assert_eq!(result, expected_lines.join("\n"));
     }
+
+    #[test]
+    fn test_binary_summary() {
+        assert_eq!(binary_summary(&[]), "0 bytes, sha256 e3b0c44298fc1c14");
+        assert_eq!(
+            binary_summary(&[0x00, 0x9f, 0x92, 0x96]),
+            "4 bytes, sha256 b02a591131217cb5"
+        );
+    }
 }
@@ -1,23 +1,38 @@
 //! The engine module contains the core logic for dotpatina operations.
 
 use std::{
-    fs,
+    collections::HashMap,
+    fs, io,
     path::{Path, PathBuf},
 };
 
 use colored::Colorize;
 use interface::PatinaInterface;
 use log::info;
+use serde_json::Value;
 use similar::TextDiff;
 
+mod hooks;
 pub mod interface;
 
 use crate::templating::PatinaFileRender;
 use crate::{
-    diff::DiffAnalysis,
-    patina::Patina,
+    block,
+    diff::{binary_summary, DiffAnalysis},
+    merge,
+    patina::{
+        format::Format,
+        patina_file::{FileMode, PatinaFile},
+        secrets::SecretKey,
+        tags::TagExpression,
+        version, Patina, PatinaHook,
+    },
     templating,
-    utils::{Error, Result},
+    utils::{
+        create_symlink, get_file_mode, is_binary, set_file_mode, write_file_with_mode, Error,
+        Result,
+    },
+    validation,
 };
 
 /// The PatinaEngine is the main driver of logic for dotpatina operations
@@ -31,11 +46,17 @@ where
     /// The path to the patina file on disk
     patina_path: PathBuf,
 
-    /// The set of tags to filter on
-    tags: Option<Vec<String>>,
+    /// The tag expression to filter files on
+    tags: Option<TagExpression>,
 
     /// A list of variables path files
     variables_files: Vec<PathBuf>,
+
+    /// The key for decrypting encrypted variables files
+    secret_key: Option<SecretKey>,
+
+    /// A list of `key.path=value` variables, overlaid after the variables files
+    set_vars: Vec<String>,
 }
 
 impl<'a, PI> PatinaEngine<'a, PI>
@@ -46,34 +67,72 @@ where
     pub fn new(
         pi: &'a PI,
         patina_path: &Path,
-        tags: Vec<String>,
+        tags: Option<TagExpression>,
         variables_files: Vec<PathBuf>,
     ) -> PatinaEngine<'a, PI> {
-        let tags = match &*tags {
-            [] => None,
-            _ => Some(tags),
-        };
         PatinaEngine {
             pi,
             patina_path: patina_path.to_path_buf(),
             tags,
             variables_files,
+            secret_key: None,
+            set_vars: vec![],
         }
     }
 
+    /// Set the `key.path=value` variables to overlay after the variables files
+    pub fn with_set_vars(mut self, set_vars: Vec<String>) -> PatinaEngine<'a, PI> {
+        self.set_vars = set_vars;
+        self
+    }
+
+    /// Set the key for decrypting encrypted variables files
+    pub fn with_secret_key(mut self, secret_key: Option<SecretKey>) -> PatinaEngine<'a, PI> {
+        self.secret_key = secret_key;
+        self
+    }
+
+    /// Load the Patina and its variables
+    fn load_patina(&self) -> Result<Patina> {
+        let mut patina = Patina::from_file(&self.patina_path)?;
+        patina.load_vars_exec()?;
+        let mut variables_files = patina.get_vars_dirs_files();
+        variables_files.extend(self.variables_files.iter().cloned());
+        patina.load_vars_files(variables_files, self.secret_key.as_ref())?;
+        patina.load_set_vars(&self.set_vars)?;
+
+        // The vars can hold decrypted secrets, so only their names are logged
+        let vars = patina.vars.take();
+        let var_names: Vec<&String> = vars
+            .iter()
+            .filter_map(Value::as_object)
+            .flat_map(|v| v.keys())
+            .collect();
+        info!(
+            "got patina: {:#?}
+with vars: {:?}",
+            patina, var_names
+        );
+        patina.vars = vars;
+        Ok(patina)
+    }
+
     /// Renders a Patina
     pub fn render_patina(&self) -> Result<()> {
-        let mut patina = Patina::from_toml_file(&self.patina_path)?;
-        patina.load_vars_files(self.variables_files.clone())?;
-
-        info!("got patina: {:#?}", patina);
+        let patina = self.load_patina()?;
         let render = templating::render_patina(&patina, self.tags.clone())?;
 
         self.pi
-            .output(format!("Rendered {} files\n\n", render.len()));
-        for r in render.iter() {
+            .output(format!("Rendered {} files\n\n", render.files.len()));
+        self.output_skipped_files(&render.skipped_files);
+        for r in render.files.iter() {
             self.pi.output_file_header(&r.patina_file.template);
-            self.pi.output(format!("{}\n", r.render_str));
+            match &r.render_bytes {
+                Some(bytes) => self
+                    .pi
+                    .output(format!("binary file ({})\n", binary_summary(bytes))),
+                None => self.pi.output(format!("{}\n", r.render_str)),
+            }
         }
 
         Ok(())
@@ -81,13 +140,15 @@ where
 
     /// Applies all the Patina files
     pub fn apply_patina(&self, use_trash: bool) -> Result<()> {
-        let mut patina = Patina::from_toml_file(&self.patina_path)?;
-        patina.load_vars_files(self.variables_files.clone())?;
-
-        info!("got patina: {:#?}", patina);
-        let mut render = templating::render_patina(&patina, self.tags.clone())?;
+        let patina = self.load_patina()?;
+        let render = templating::render_patina(&patina, self.tags.clone())?;
+        let mut render_files = render.files;
 
-        let any_changes = self.generate_and_display_diffs(&patina, &mut render);
+        self.merge_targets(&patina, &mut render_files)?;
+        self.output_skipped_files(&render.skipped_files);
+        let absent_targets = self.get_absent_targets(&patina, &render_files)?;
+        let any_changes =
+            self.generate_and_display_diffs(&patina, &mut render_files, &absent_targets);
 
         // If there are no changes, quit
         if !any_changes {
@@ -101,9 +162,27 @@ where
             return Ok(());
         }
 
+        if !patina.hooks.pre_apply.is_empty() {
+            self.pi.output("\nRunning pre-apply hooks\n");
+            self.run_patina_hooks(&patina, &patina.hooks.pre_apply)?;
+        }
+
         // Write out all files
         self.pi.output("\nApplying patina files\n");
-        let num_trashed = self.apply_renders(&patina, render, use_trash)?;
+        let on_change_hooks = self.get_on_change_hooks(&patina, &render_files);
+        let mut num_trashed = self.apply_renders(&patina, render_files, use_trash)?;
+        num_trashed += self.remove_absent_targets(absent_targets, use_trash)?;
+
+        if !on_change_hooks.is_empty() {
+            self.pi.output("\nRunning on-change hooks\n");
+            for (command, dir) in on_change_hooks {
+                hooks::run_hooks(self.pi, &[command], &dir)?;
+            }
+        }
+        if !patina.hooks.post_apply.is_empty() {
+            self.pi.output("\nRunning post-apply hooks\n");
+            self.run_patina_hooks(&patina, &patina.hooks.post_apply)?;
+        }
 
         self.pi.output("Done");
         if num_trashed > 0 {
@@ -117,10 +196,242 @@ where
         Ok(())
     }
 
+    /// Get the targets of the patina's absent files that currently exist and need to be removed.
+    /// Returns an error if an absent target is also the target of a rendered file.
+    fn get_absent_targets(
+        &self,
+        patina: &Patina,
+        render: &[PatinaFileRender],
+    ) -> Result<Vec<PathBuf>> {
+        let mut absent_targets = vec![];
+        for absent_file in patina.absent_for_tags(self.tags.clone()) {
+            let target_path = patina.get_absent_target_path(absent_file);
+            if render
+                .iter()
+                .any(|r| patina.get_target_path(&r.patina_file) == target_path)
+            {
+                return Err(Error::Message(format!(
+                    "{}: an absent target can't also be the target of a file",
+                    target_path.display()
+                )));
+            }
+
+            // Only files and links are removed, never whole directories
+            if (target_path.is_file() || target_path.is_symlink())
+                && !absent_targets.contains(&target_path)
+            {
+                absent_targets.push(target_path);
+            }
+        }
+        Ok(absent_targets)
+    }
+
+    /// Run pre-apply or post-apply hooks in order, each from the directory of the patina that defined it
+    fn run_patina_hooks(&self, patina: &Patina, patina_hooks: &[PatinaHook]) -> Result<()> {
+        patina_hooks.iter().try_for_each(|hook| {
+            hooks::run_hooks(
+                self.pi,
+                std::slice::from_ref(&hook.command),
+                &patina.get_hook_base_path(hook),
+            )
+        })
+    }
+
+    /// Get the on-change hooks of all changed files, along with the directory to run each one from.
+    /// A command that is shared by several changed files only runs once.
+    fn get_on_change_hooks(
+        &self,
+        patina: &Patina,
+        render: &[PatinaFileRender],
+    ) -> Vec<(String, PathBuf)> {
+        let mut result: Vec<(String, PathBuf)> = vec![];
+        for r in render.iter().filter(|r| r.any_changes == Some(true)) {
+            let dir = patina.get_patina_file_base_path(&r.patina_file);
+            for command in &r.patina_file.on_change {
+                if !result.iter().any(|(c, d)| c == command && *d == dir) {
+                    result.push((command.clone(), dir.clone()));
+                }
+            }
+        }
+        result
+    }
+
+    /// Validates a Patina without writing any files, and outputs all the problems found
+    pub fn validate_patina(&self) -> Result<()> {
+        let mut issues = validation::find_unknown_keys(&self.patina_path);
+        if let Some(patina) = self.load_patina_for_validation(&mut issues) {
+            issues.extend(validation::find_facts_vars(&self.patina_path, &patina));
+            issues.extend(validation::validate_patina(&patina, self.tags.clone()));
+        }
+
+        if issues.is_empty() {
+            self.pi.output(format!("{}\n", "Patina is valid".green()));
+            return Ok(());
+        }
+
+        self.pi.output(
+            format!("Found {} problems in the patina\n", issues.len())
+                .red()
+                .to_string(),
+        );
+        for issue in issues.iter() {
+            self.pi.output(format!("  {}\n", issue));
+        }
+
+        Err(Error::InvalidPatina(issues.len()))
+    }
+
+    /// Load the Patina and its variables like [Self::load_patina], but report every problem as a validation issue
+    /// instead of stopping at the first one. Returns `None` if the Patina itself can't be loaded.
+    fn load_patina_for_validation(
+        &self,
+        issues: &mut Vec<validation::ValidationIssue>,
+    ) -> Option<Patina> {
+        let mut patina = match Patina::from_file(&self.patina_path) {
+            Ok(patina) => patina,
+            Err(e) => {
+                let message = format!("failed to load patina: {}", e);
+                issues.push(validation::ValidationIssue::new(&self.patina_path, message));
+                return None;
+            }
+        };
+
+        let mut report = |result: Result<()>| {
+            if let Err(e) = result {
+                let message = format!("failed to load vars: {}", e);
+                issues.push(validation::ValidationIssue::new(&self.patina_path, message));
+            }
+        };
+        report(patina.load_vars_exec());
+        let mut variables_files = patina.get_vars_dirs_files();
+        variables_files.extend(self.variables_files.iter().cloned());
+        for variables_file in variables_files {
+            report(patina.load_vars_files(vec![variables_file], self.secret_key.as_ref()));
+        }
+        for set_var in &self.set_vars {
+            report(patina.load_set_vars(std::slice::from_ref(set_var)));
+        }
+
+        Some(patina)
+    }
+
+    /// Rewrite the patina file to the current schema version, keeping its comments and formatting.
+    /// Included patina files are not migrated.
+    pub fn migrate_patina(&self) -> Result<()> {
+        let data = match fs::read_to_string(&self.patina_path) {
+            Ok(data) => data,
+            Err(e) => return Err(Error::FileRead(self.patina_path.clone(), e)),
+        };
+
+        let file_version = version::read_version(&self.patina_path, &data)?;
+        if file_version == version::CURRENT_VERSION {
+            self.pi.output(format!(
+                "{} is already at version {}\n",
+                self.patina_path.display(),
+                file_version
+            ));
+            return Ok(());
+        }
+        if Format::from_path(&self.patina_path) != Format::Toml {
+            return Err(Error::Message(format!(
+                "{}: only TOML patina files can be migrated, set `version = {}` and update the files by hand",
+                self.patina_path.display(),
+                version::CURRENT_VERSION
+            )));
+        }
+
+        let migrated = version::migrate_toml(&self.patina_path, &data, file_version)?;
+        let diff = TextDiff::from_lines(&data, &migrated);
+        self.pi.output_file_header(&self.patina_path);
+        self.pi.output(format!("{}\n", diff.to_string()));
+
+        if let Err(e) = fs::write(&self.patina_path, migrated) {
+            return Err(Error::FileWrite(self.patina_path.clone(), e));
+        }
+        self.pi.output(format!(
+            "Migrated from version {} to version {}\n",
+            file_version,
+            version::CURRENT_VERSION
+        ));
+        Ok(())
+    }
+
+    /// Output the files that were skipped because their `when` condition was false
+    fn output_skipped_files(&self, skipped_files: &[&PatinaFile]) {
+        if skipped_files.is_empty() {
+            return;
+        }
+
+        self.pi.output(format!(
+            "Skipped {} files {}\n",
+            skipped_files.len(),
+            "(when condition is false)".bright_black()
+        ));
+        for pf in skipped_files {
+            self.pi.output(format!(
+                "  {}\n",
+                pf.template.display().to_string().yellow()
+            ));
+        }
+        self.pi.output("\n");
+    }
+
+    /// Merge the renders of files that only own part of their target into the current contents of the target,
+    /// for managed blocks and structured merges.
+    /// Files that share a target are merged in order, each starting from the contents merged by the ones before it.
+    fn merge_targets(&self, patina: &Patina, render: &mut [PatinaFileRender]) -> Result<()> {
+        let mut merged_targets: HashMap<PathBuf, String> = HashMap::new();
+        for r in render.iter_mut() {
+            let block_name = r.patina_file.block_name();
+            if block_name.is_none() && r.patina_file.merge.is_none() {
+                continue;
+            }
+
+            let target_path = patina.get_target_path(&r.patina_file);
+            if r.render_bytes.is_some() {
+                return Err(Error::Message(format!(
+                    "{}: a binary file can't be merged into its target",
+                    r.patina_file.template.display()
+                )));
+            }
+
+            let target_str = match merged_targets.remove(&target_path) {
+                Some(target_str) => target_str,
+                None => match fs::read_to_string(&target_path) {
+                    Ok(target_str) => target_str,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+                    Err(e) => return Err(Error::FileRead(target_path, e)),
+                },
+            };
+
+            let target_str = match (&block_name, r.patina_file.merge) {
+                (Some(block_name), _) => {
+                    if !r.render_str.ends_with('\n') {
+                        r.render_str.push('\n');
+                    }
+                    block::replace_block(&target_path, &target_str, block_name, &r.render_str)?
+                }
+                (None, Some(format)) => merge::merge_into_target(
+                    format,
+                    &target_path,
+                    &target_str,
+                    &r.patina_file.template,
+                    &r.render_str,
+                )?,
+                (None, None) => continue,
+            };
+            merged_targets.insert(target_path, target_str.clone());
+            r.target_str = Some(target_str);
+        }
+
+        Ok(())
+    }
+
     fn generate_and_display_diffs(
         &self,
         patina: &Patina,
         render: &mut Vec<PatinaFileRender>,
+        absent_targets: &[PathBuf],
     ) -> bool {
         let mut any_changes = false;
 
@@ -129,22 +440,28 @@ where
 
         // Generate and display diffs
         for r in render.iter_mut() {
-            let target_path = patina.get_patina_path(&r.patina_file.target);
-
-            let target_file_str = fs::read_to_string(&target_path).unwrap_or_default();
-            let diff = TextDiff::from_lines(&target_file_str, &r.render_str);
+            let target_path = patina.get_target_path(&r.patina_file);
+            let (file_changes, diff_str) = match r.patina_file.link {
+                true => self.generate_link_diff(patina, r, &target_path),
+                false => self.generate_file_diff(r, &target_path),
+            };
 
-            r.any_changes = Some(diff.any_changes());
+            r.any_changes = Some(file_changes);
             if r.any_changes.unwrap() {
                 any_changes = true
             }
 
             if r.any_changes.unwrap() {
-                files_with_changes.push((target_path, diff.to_string()));
+                files_with_changes.push((target_path, diff_str));
             } else {
-                files_without_changes.push((target_path, diff.to_string()));
+                files_without_changes.push((target_path, diff_str));
             }
         }
+        for target_path in absent_targets {
+            let diff_str = self.generate_absent_diff(target_path);
+            files_with_changes.push((target_path.clone(), diff_str));
+            any_changes = true;
+        }
 
         let any_unchanged_files = !files_without_changes.is_empty();
         if any_unchanged_files {
@@ -173,6 +490,137 @@ where
         any_changes
     }
 
+    /// Generate a diff between a target file and its render.
+    /// Returns whether there are any changes and the diff to display.
+    fn generate_file_diff(&self, r: &PatinaFileRender, target_path: &Path) -> (bool, String) {
+        let (mut file_changes, mut diff_str) = match (&r.target_str, &r.render_bytes) {
+            (Some(_), _) if r.patina_file.is_block() => {
+                self.generate_block_diff(&r.patina_file, &r.render_str, target_path)
+            }
+            (Some(target_str), _) => {
+                let target_file_str = fs::read_to_string(target_path).unwrap_or_default();
+                let diff = TextDiff::from_lines(&target_file_str, target_str);
+                (diff.any_changes(), diff.to_string())
+            }
+            (None, Some(bytes)) => self.generate_binary_diff(bytes, target_path),
+            (None, None) => {
+                let target_file_str = fs::read_to_string(target_path).unwrap_or_default();
+                let diff = TextDiff::from_lines(&target_file_str, &r.render_str);
+                (diff.any_changes(), diff.to_string())
+            }
+        };
+
+        // A mode change is a change, even if the file contents are identical
+        if let Some(mode) = r.patina_file.permissions() {
+            match get_file_mode(target_path) {
+                Some(target_mode) if target_mode != mode => {
+                    let mode_str = format!(
+                        "mode {} → {}\n",
+                        FileMode::Permissions(target_mode),
+                        FileMode::Permissions(mode)
+                    );
+                    diff_str = mode_str.yellow().to_string() + &diff_str;
+                    file_changes = true;
+                }
+                _ => {}
+            }
+        }
+
+        (file_changes, diff_str)
+    }
+
+    /// Generate a diff between the managed block in a target file and its render.
+    /// Only the block is diffed, and only a change to the block is a change.
+    /// Returns whether there are any changes and the diff to display.
+    fn generate_block_diff(
+        &self,
+        patina_file: &PatinaFile,
+        block_str: &str,
+        target_path: &Path,
+    ) -> (bool, String) {
+        let block_name = patina_file.block_name().unwrap_or_default();
+        let current_target_str = fs::read_to_string(target_path).unwrap_or_default();
+        let current_block_str = block::get_block(target_path, &current_target_str, &block_name)
+            .ok()
+            .flatten();
+
+        let diff = TextDiff::from_lines(current_block_str.unwrap_or_default(), block_str);
+        let block_header = format!("managed block {}\n", block_name);
+        let diff_str = block_header.yellow().to_string() + &diff.to_string();
+        (current_block_str != Some(block_str), diff_str)
+    }
+
+    /// Generate a summary of the differences between a target file and binary contents.
+    /// Returns whether there are any changes and the summary to display.
+    fn generate_binary_diff(&self, bytes: &[u8], target_path: &Path) -> (bool, String) {
+        match fs::read(target_path) {
+            Ok(target_bytes) if target_bytes == bytes => {
+                (false, format!("binary file ({})\n", binary_summary(bytes)))
+            }
+            Ok(target_bytes) => {
+                let diff_str = format!(
+                    "binary file changed\n- {}\n+ {}\n",
+                    binary_summary(&target_bytes).red(),
+                    binary_summary(bytes).green()
+                );
+                (true, diff_str)
+            }
+            Err(_) => {
+                let diff_str =
+                    format!("binary file created\n+ {}\n", binary_summary(bytes).green());
+                (true, diff_str)
+            }
+        }
+    }
+
+    /// Generate a diff that shows the removal of an absent target
+    fn generate_absent_diff(&self, target_path: &Path) -> String {
+        if let Ok(link_path) = fs::read_link(target_path) {
+            return format!("delete link → {}\n", link_path.display())
+                .red()
+                .to_string();
+        }
+
+        let target_bytes = fs::read(target_path).unwrap_or_default();
+        if is_binary(&target_bytes) {
+            return format!(
+                "binary file deleted\n- {}\n",
+                binary_summary(&target_bytes).red()
+            );
+        }
+
+        let target_str = String::from_utf8_lossy(&target_bytes);
+        let diff = TextDiff::from_lines(target_str.as_ref(), "");
+        "file deleted\n".red().to_string() + &diff.to_string()
+    }
+
+    /// Generate a diff between a target and the link to its template.
+    /// Returns whether there are any changes and the diff to display.
+    fn generate_link_diff(
+        &self,
+        patina: &Patina,
+        r: &PatinaFileRender,
+        target_path: &Path,
+    ) -> (bool, String) {
+        let template_path = patina.get_template_path(&r.patina_file);
+        let diff_str = match fs::read_link(target_path) {
+            Ok(link_path) if link_path == template_path => {
+                return (false, format!("link → {}\n", template_path.display()));
+            }
+            Ok(link_path) => format!(
+                "retarget link {} → {}\n",
+                link_path.display(),
+                template_path.display()
+            ),
+            Err(_) if target_path.exists() => {
+                format!("replace file with link → {}\n", template_path.display())
+            }
+            Err(_) => format!("create link → {}\n", template_path.display()),
+        };
+
+        (true, diff_str.green().to_string())
+    }
+
     fn apply_renders(
         &self,
         patina: &Patina,
@@ -181,7 +629,7 @@ where
     ) -> Result<usize> {
         let mut num_trashed = 0;
         for r in render.iter() {
-            let target_path = patina.get_patina_path(&r.patina_file.target);
+            let target_path = patina.get_target_path(&r.patina_file);
             self.pi.output(format!("   {}", target_path.display()));
 
             if r.any_changes == Some(false) {
@@ -193,12 +641,42 @@ where
                 continue;
             }
 
-            // If the target file exists and there are changes, trash it
-            if use_trash && target_path.is_file() && r.any_changes == Some(true) {
+            let template_path = patina.get_template_path(&r.patina_file);
+            let is_template_link = fs::read_link(&target_path).is_ok_and(|p| p == template_path);
+
+            // If only the mode changed, set it in place rather than replacing the file
+            if let Some(mode) = r.patina_file.permissions() {
+                let unchanged = !r.patina_file.link
+                    && !is_template_link
+                    && fs::read(&target_path).is_ok_and(|c| c == r.contents());
+                if unchanged {
+                    if let Err(e) = set_file_mode(&target_path, mode) {
+                        return Err(Error::FileWrite(target_path, e));
+                    }
+                    self.pi.output(" ✓\n".green().to_string());
+                    continue;
+                }
+            }
+
+            // If the target file exists and there are changes, trash it.
+            // Links to the template are removed rather than trashed, so that writes don't go to the template.
+            if target_path.is_symlink() && (r.patina_file.link || is_template_link) {
+                if let Err(e) = fs::remove_file(&target_path) {
+                    return Err(Error::FileWrite(target_path, e));
+                }
+            } else if use_trash
+                && target_path.is_file()
+                && r.any_changes == Some(true)
+                && r.target_str.is_none()
+            {
                 if let Err(e) = trash::delete(&target_path) {
                     return Err(Error::MoveFileToTrash(e));
                 }
                 num_trashed += 1;
+            } else if r.patina_file.link && target_path.exists() {
+                if let Err(e) = fs::remove_file(&target_path) {
+                    return Err(Error::FileWrite(target_path, e));
+                }
             }
 
             // Create parent directories and write file
@@ -207,8 +685,18 @@ where
                     return Err(Error::FileWrite(target_path, e));
                 }
             }
-            if let Err(e) = fs::write(&target_path, &r.render_str) {
-                return Err(Error::FileWrite(target_path.clone(), e));
+            if r.patina_file.link {
+                if let Err(e) = create_symlink(&template_path, &target_path) {
+                    return Err(Error::FileWrite(target_path.clone(), e));
+                }
+            } else {
+                let written = match r.patina_file.permissions() {
+                    Some(mode) => write_file_with_mode(&target_path, r.contents(), mode),
+                    None => fs::write(&target_path, r.contents()),
+                };
+                if let Err(e) = written {
+                    return Err(Error::FileWrite(target_path.clone(), e));
+                }
             }
 
             self.pi.output(" ✓\n".green().to_string());
@@ -216,11 +704,42 @@ where
 
         Ok(num_trashed)
     }
+
+    /// Remove the targets of absent files, moving files to the trash if `use_trash` is set.
+    /// Links are always removed, so that the files they point to are left alone.
+    /// Returns the number of files moved to the trash.
+    fn remove_absent_targets(
+        &self,
+        absent_targets: Vec<PathBuf>,
+        use_trash: bool,
+    ) -> Result<usize> {
+        let mut num_trashed = 0;
+        for target_path in absent_targets {
+            self.pi.output(format!("   {}", target_path.display()));
+
+            if use_trash && !target_path.is_symlink() {
+                if let Err(e) = trash::delete(&target_path) {
+                    return Err(Error::MoveFileToTrash(e));
+                }
+                num_trashed += 1;
+            } else if let Err(e) = fs::remove_file(&target_path) {
+                return Err(Error::FileWrite(target_path, e));
+            }
+
+            self.pi
+                .output(format!(" {} {}\n", "✓".green(), "(removed)".bright_black()));
+        }
+
+        Ok(num_trashed)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{engine::interface::test::TestPatinaInterface, tests::test_utils::TmpTestDir};
+    use crate::{
+        engine::interface::test::TestPatinaInterface, tests::test_utils::TmpTestDir,
+        utils::normalize_path,
+    };
 
     use super::*;
 
@@ -249,7 +768,7 @@ Templates use the Handebars templating language. For more information, see <http
 
         colored::control::set_override(false);
         let pi = TestPatinaInterface::new();
-        let engine = PatinaEngine::new(&pi, &patina_path, vec![], vec![]);
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
 
         let render = engine.render_patina();
 
@@ -268,11 +787,46 @@ Templates use the Handebars templating language. For more information, see <http
         );
     }
 
+    #[test]
+    fn test_render_patina_vars_dirs() {
+        let tmp_dir = TmpTestDir::new();
+        fs::create_dir_all(tmp_dir.get_file_path("vars")).unwrap();
+        let patina_path = tmp_dir.write_file(
+            "vars_dirs_patina.toml",
+            r#"
+                name = "vars-dirs-patina"
+                vars_dirs = ["vars"]
+
+                [[files]]
+                template = "template.txt.hbs"
+                target = "template.txt"
+            "#,
+        );
+        tmp_dir.write_file("template.txt.hbs", "{{ a }} {{ b }} {{ c }}\n");
+        tmp_dir.write_file(
+            "vars/default.toml",
+            "a = \"default\"\nb = \"default\"\nc = \"default\"",
+        );
+        tmp_dir.write_file("vars/local.toml", "b = \"local\"\nc = \"local\"");
+        let vars_path = tmp_dir.write_file("explicit.toml", "c = \"explicit\"");
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![vars_path]);
+
+        let render = engine.render_patina();
+
+        assert!(render.is_ok());
+        assert_eq!(
+            pi.get_all_output(),
+            "Rendered 1 files\n\ntemplate.txt.hbs\ndefault local explicit\n\n"
+        );
+    }
+
     #[test]
     fn test_render_patina_failed_file_load() {
         let patina_path = PathBuf::from("this/path/does/not/exist.toml");
         let pi = TestPatinaInterface::new();
-        let engine = PatinaEngine::new(&pi, &patina_path, vec![], vec![]);
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
 
         let render = engine.render_patina();
         assert!(render.is_err());
@@ -298,7 +852,7 @@ Templates use the Handebars templating language. For more information, see <http
         );
 
         let pi = TestPatinaInterface::new();
-        let engine = PatinaEngine::new(&pi, &patina_path, vec![], vec![]);
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
 
         let render = engine.render_patina();
         assert!(render.is_err());
@@ -328,7 +882,7 @@ Templates use the Handebars templating language. For more information, see <http
 "#);
 
         let pi = TestPatinaInterface::new();
-        let engine = PatinaEngine::new(&pi, &patina_path, vec![], vec![]);
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
 
         let apply = engine.apply_patina(false);
 
@@ -378,7 +932,7 @@ Templates use the Handebars templating language. For more information, see <http
 
         let mut pi = TestPatinaInterface::new();
         pi.confirm_apply = false;
-        let engine = PatinaEngine::new(&pi, &patina_path, vec![], vec![]);
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
 
         let apply = engine.apply_patina(false);
 
@@ -398,7 +952,7 @@ Templates use the Handebars templating language. For more information, see <http
         );
 
         let pi = TestPatinaInterface::new();
-        let engine = PatinaEngine::new(&pi, &patina_path, vec![], vec![]);
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
         let apply = engine.apply_patina(false);
 
         assert!(apply.is_ok());
@@ -406,4 +960,976 @@ Templates use the Handebars templating language. For more information, see <http
             .get_all_output()
             .contains("No file changes detected in the patina"));
     }
+
+    #[test]
+    fn test_apply_patina_template_directory() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "directory_patina.toml",
+            r#"
+                name = "directory-patina"
+                description = "This is a Patina for a template directory"
+
+                [vars]
+                name = "Patina"
+
+                [[files]]
+                template = "templates"
+                target = "output"
+            "#,
+        );
+        fs::create_dir_all(tmp_dir.get_file_path("templates/nested")).unwrap();
+        tmp_dir.write_file("templates/a.txt.hbs", "Hello, {{ name }}!\n");
+        tmp_dir.write_file("templates/nested/b.txt.hbs", "Goodbye, {{ name }}!\n");
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+
+        let apply = engine.apply_patina(false);
+
+        assert!(apply.is_ok());
+        let output = pi.get_all_output();
+        assert!(output.contains("output/a.txt\n+   1 | Hello, Patina!"));
+        assert!(output.contains("output/nested/b.txt\n+   1 | Goodbye, Patina!"));
+
+        assert_eq!(
+            fs::read_to_string(tmp_dir.get_file_path("output/a.txt")).unwrap(),
+            "Hello, Patina!\n"
+        );
+        assert_eq!(
+            fs::read_to_string(tmp_dir.get_file_path("output/nested/b.txt")).unwrap(),
+            "Goodbye, Patina!\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_patina_file_mode() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "mode_patina.toml",
+            r#"
+                name = "mode-patina"
+                description = "This is a Patina for an executable file"
+
+                [[files]]
+                template = "run.sh"
+                target = "output/run.sh"
+                mode = "0755"
+            "#,
+        );
+        tmp_dir.write_file("run.sh", "echo 'Hello, Patina!'\n");
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+
+        let apply = engine.apply_patina(false);
+
+        assert!(apply.is_ok());
+        assert_eq!(
+            get_file_mode(tmp_dir.get_file_path("output/run.sh")),
+            Some(0o755)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_patina_file_mode_change_without_content_change() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "mode_patina.toml",
+            r#"
+                name = "mode-patina"
+                description = "This is a Patina for a private file"
+
+                [[files]]
+                template = "config"
+                target = "config.out"
+                mode = "0600"
+            "#,
+        );
+        tmp_dir.write_file("config", "Host *\n");
+        let target_path = tmp_dir.write_file("config.out", "Host *\n");
+        set_file_mode(&target_path, 0o644).unwrap();
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+
+        let apply = engine.apply_patina(false);
+
+        assert!(apply.is_ok());
+        let output = pi.get_all_output();
+        assert!(output.contains("mode 0644 → 0600"));
+        assert!(!output.contains("No file changes detected in the patina"));
+        assert_eq!(get_file_mode(&target_path), Some(0o600));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_patina_file_mode_change_is_not_trashed() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "mode_patina.toml",
+            r#"
+                name = "mode-patina"
+                description = "This is a Patina for a private file"
+
+                [[files]]
+                template = "config"
+                target = "config.out"
+                mode = "0600"
+            "#,
+        );
+        tmp_dir.write_file("config", "Host *\n");
+        let target_path = tmp_dir.write_file("config.out", "Host *\n");
+        set_file_mode(&target_path, 0o644).unwrap();
+        let inode = fs::metadata(&target_path).unwrap().ino();
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+
+        let apply = engine.apply_patina(true);
+
+        assert!(apply.is_ok());
+        let output = pi.get_all_output();
+        assert!(!output.contains("original files moved to trash"));
+        assert_eq!(get_file_mode(&target_path), Some(0o600));
+        assert_eq!(fs::metadata(&target_path).unwrap().ino(), inode);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_patina_link() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "link_patina.toml",
+            r#"
+                name = "link-patina"
+                description = "This is a Patina for a linked file"
+
+                [[files]]
+                template = "vimrc"
+                target = "output/.vimrc"
+                link = true
+            "#,
+        );
+        let template_path = tmp_dir.write_file("vimrc", "set number {{ not_templated }}\n");
+        let template_path = normalize_path(template_path).unwrap();
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+
+        let apply = engine.apply_patina(false);
+
+        assert!(apply.is_ok());
+        assert!(pi
+            .get_all_output()
+            .contains(&format!("create link → {}", template_path.display())));
+
+        let target_path = tmp_dir.get_file_path("output/.vimrc");
+        assert_eq!(fs::read_link(&target_path).unwrap(), template_path);
+        assert_eq!(
+            fs::read_to_string(&target_path).unwrap(),
+            "set number {{ not_templated }}\n"
+        );
+
+        // Applying again does nothing
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+        let apply = engine.apply_patina(false);
+
+        assert!(apply.is_ok());
+        assert!(pi
+            .get_all_output()
+            .contains("No file changes detected in the patina"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_patina_link_replaces_file() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "link_patina.toml",
+            r#"
+                name = "link-patina"
+                description = "This is a Patina for a linked file"
+
+                [[files]]
+                template = "vimrc"
+                target = ".vimrc"
+                link = true
+            "#,
+        );
+        let template_path = tmp_dir.write_file("vimrc", "set number\n");
+        let template_path = normalize_path(template_path).unwrap();
+        let target_path = tmp_dir.write_file(".vimrc", "set nonumber\n");
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+
+        let apply = engine.apply_patina(false);
+
+        assert!(apply.is_ok());
+        assert!(pi.get_all_output().contains(&format!(
+            "replace file with link → {}",
+            template_path.display()
+        )));
+        assert_eq!(fs::read_link(&target_path).unwrap(), template_path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_patina_link_retarget() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "link_patina.toml",
+            r#"
+                name = "link-patina"
+                description = "This is a Patina for a linked file"
+
+                [[files]]
+                template = "vimrc"
+                target = ".vimrc"
+                link = true
+            "#,
+        );
+        let template_path = tmp_dir.write_file("vimrc", "set number\n");
+        let template_path = normalize_path(template_path).unwrap();
+        let old_template_path = tmp_dir.write_file("old-vimrc", "set nonumber\n");
+        let target_path = tmp_dir.get_file_path(".vimrc");
+        create_symlink(&old_template_path, &target_path).unwrap();
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+
+        let apply = engine.apply_patina(false);
+
+        assert!(apply.is_ok());
+        assert!(pi.get_all_output().contains(&format!(
+            "retarget link {} → {}",
+            old_template_path.display(),
+            template_path.display()
+        )));
+        assert_eq!(fs::read_link(&target_path).unwrap(), template_path);
+        assert_eq!(
+            fs::read_to_string(&old_template_path).unwrap(),
+            "set nonumber\n"
+        );
+    }
+
+    #[test]
+    fn test_render_patina_reports_skipped_files() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "when_patina.toml",
+            r#"
+                name = "when-patina"
+                description = "This is a Patina with conditional files"
+
+                [vars]
+                os = "linux"
+
+                [[files]]
+                template = "linux.txt"
+                target = "linux.txt"
+                when = "eq os 'linux'"
+
+                [[files]]
+                template = "macos.txt"
+                target = "macos.txt"
+                when = "eq os 'macos'"
+            "#,
+        );
+        tmp_dir.write_file("linux.txt", "This is linux.");
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+
+        let render = engine.render_patina();
+
+        assert!(render.is_ok());
+        assert_eq!(
+            pi.get_all_output(),
+            r#"Rendered 1 files
+
+Skipped 1 files (when condition is false)
+  macos.txt
+
+linux.txt
+This is linux.
+"#
+        );
+    }
+
+    #[test]
+    fn test_validate_patina() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "valid_patina.toml",
+            r#"
+                name = "valid-patina"
+
+                [vars]
+                name = "Patina"
+
+                [[files]]
+                template = "template.txt.hbs"
+                target = "template.txt"
+            "#,
+        );
+        tmp_dir.write_file("template.txt.hbs", "Hello, {{ name }}!");
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+
+        let validate = engine.validate_patina();
+
+        assert!(validate.is_ok());
+        assert_eq!(pi.get_all_output(), "Patina is valid\n");
+        assert!(!tmp_dir.get_file_path("template.txt").exists());
+    }
+
+    #[test]
+    fn test_validate_patina_reports_all_problems() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "invalid_patina.toml",
+            r#"
+                name = "invalid-patina"
+                tgas = ["typo"]
+
+                [[files]]
+                template = "missing.txt"
+                target = "missing.txt"
+
+                [[files]]
+                template = "template.txt.hbs"
+                target = "template.txt"
+            "#,
+        );
+        tmp_dir.write_file("template.txt.hbs", "Hello, {{ name }}!");
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+
+        let validate = engine.validate_patina();
+
+        assert!(validate.is_err());
+        assert_eq!(validate.unwrap_err().as_invalid_patina(), Some(&3));
+        let output = pi.get_all_output();
+        assert!(output.contains("Found 3 problems in the patina"));
+        assert!(output.contains("invalid_patina.toml: unknown key `tgas`"));
+        assert!(output.contains("missing.txt: failed to read template"));
+        assert!(output.contains("template.txt.hbs: undefined variable `name`"));
+        assert!(!tmp_dir.get_file_path("template.txt").exists());
+    }
+
+    #[test]
+    fn test_validate_patina_duplicate_targets_with_include() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file("base.toml", "name = \"base\"\n");
+        let patina_path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "overlay"
+                include = ["base.toml"]
+
+                [[files]]
+                template = "a.txt"
+                target = "output.txt"
+
+                [[files]]
+                template = "b.txt"
+                target = "output.txt"
+            "#,
+        );
+        tmp_dir.write_file("a.txt", "a\n");
+        tmp_dir.write_file("b.txt", "b\n");
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+
+        let validate = engine.validate_patina();
+
+        assert!(validate.is_err());
+        assert!(pi
+            .get_all_output()
+            .contains("output.txt: target is used by multiple templates: a.txt, b.txt"));
+    }
+
+    #[test]
+    fn test_validate_patina_reports_vars_problems() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "invalid_patina.toml",
+            r#"
+                name = "invalid-patina"
+                tgas = ["typo"]
+
+                [[files]]
+                template = "missing.txt"
+                target = "missing.txt"
+            "#,
+        );
+        let vars_path = tmp_dir.write_file("vars.toml", "name = ");
+        let missing_vars_path = tmp_dir.get_file_path("missing.toml");
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![vars_path, missing_vars_path])
+            .with_set_vars(vec![String::from("name")]);
+
+        let validate = engine.validate_patina();
+
+        assert!(validate.is_err());
+        assert_eq!(validate.unwrap_err().as_invalid_patina(), Some(&5));
+        let output = pi.get_all_output();
+        assert!(output.contains("invalid_patina.toml: unknown key `tgas`"));
+        assert!(output.contains("invalid_patina.toml: failed to load vars: "));
+        assert!(output.contains("vars.toml:1:8: "));
+        assert!(output.contains("missing.toml: failed to read file"));
+        assert!(output.contains("invalid --set `name`: expected `key=value`"));
+        assert!(output.contains("missing.txt: failed to read template"));
+    }
+
+    #[test]
+    fn test_validate_patina_missing_include() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "invalid_patina.toml",
+            r#"
+                name = "invalid-patina"
+                include = ["missing.toml"]
+                tgas = ["typo"]
+            "#,
+        );
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+
+        let validate = engine.validate_patina();
+
+        assert!(validate.is_err());
+        assert_eq!(validate.unwrap_err().as_invalid_patina(), Some(&2));
+        let output = pi.get_all_output();
+        assert!(output.contains("invalid_patina.toml: unknown key `tgas`"));
+        assert!(output.contains("invalid_patina.toml: failed to load patina: "));
+        assert!(output.contains("missing.toml: failed to read file"));
+    }
+
+    #[test]
+    fn test_apply_patina_binary_file() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "binary_patina.toml",
+            r#"
+                name = "binary-patina"
+                description = "This is a Patina for a binary file"
+
+                [[files]]
+                template = "font.ttf"
+                target = "output/font.ttf"
+            "#,
+        );
+        let template_path = tmp_dir.write_file("font.ttf", [0x00, 0x9f, 0x92, 0x96]);
+        let target_path = tmp_dir.get_file_path("output/font.ttf");
+
+        // Create the file
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+        assert!(engine.apply_patina(false).is_ok());
+        assert!(pi
+            .get_all_output()
+            .contains("binary file created\n+ 4 bytes, sha256 b02a591131217cb5"));
+        assert_eq!(fs::read(&target_path).unwrap(), [0x00, 0x9f, 0x92, 0x96]);
+
+        // Apply again without changes
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+        assert!(engine.apply_patina(false).is_ok());
+        assert!(pi
+            .get_all_output()
+            .contains("No file changes detected in the patina"));
+
+        // Change the file
+        fs::write(&template_path, [0x00, 0x01]).unwrap();
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+        assert!(engine.apply_patina(false).is_ok());
+        let output = pi.get_all_output();
+        assert!(output.contains("binary file changed"));
+        assert!(output.contains("- 4 bytes, sha256 b02a591131217cb5"));
+        assert!(output.contains("+ 2 bytes"));
+        assert_eq!(fs::read(&target_path).unwrap(), [0x00, 0x01]);
+    }
+
+    #[test]
+    fn test_apply_patina_block() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "block_patina.toml",
+            r#"
+                name = "shell"
+                description = "This is a Patina for a managed block"
+
+                [vars]
+                editor = "vim"
+
+                [[files]]
+                template = "bashrc.hbs"
+                target = ".bashrc"
+                block = "shell"
+            "#,
+        );
+        tmp_dir.write_file("bashrc.hbs", "export EDITOR={{ editor }}");
+        let target_path = tmp_dir.write_file(
+            ".bashrc",
+            "# written by an installer\nexport PATH=$PATH:~/bin\n",
+        );
+
+        // Append the block
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+        assert!(engine.apply_patina(true).is_ok());
+        let output = pi.get_all_output();
+        assert!(output.contains("managed block shell"));
+        assert!(output.contains("+   1 | export EDITOR=vim"));
+        assert!(!output.contains("original files moved to trash"));
+        assert_eq!(
+            fs::read_to_string(&target_path).unwrap(),
+            r#"# written by an installer
+export PATH=$PATH:~/bin
+
+# BEGIN dotpatina shell
+export EDITOR=vim
+# END dotpatina shell
+"#
+        );
+
+        // Apply again without changes
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+        assert!(engine.apply_patina(false).is_ok());
+        assert!(pi
+            .get_all_output()
+            .contains("No file changes detected in the patina"));
+
+        // Replace the block, keeping changes made outside of it
+        let mut target_str = fs::read_to_string(&target_path).unwrap();
+        target_str.push_str("alias ll=\"ls -l\"\n");
+        fs::write(&target_path, target_str).unwrap();
+        tmp_dir.write_file(
+            "bashrc.hbs",
+            "export EDITOR={{ editor }}\nexport PAGER=less",
+        );
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+        assert!(engine.apply_patina(false).is_ok());
+        let output = pi.get_all_output();
+        assert!(output.contains("+   2 | export PAGER=less"));
+        assert!(!output.contains("alias ll"));
+        assert_eq!(
+            fs::read_to_string(&target_path).unwrap(),
+            r#"# written by an installer
+export PATH=$PATH:~/bin
+
+# BEGIN dotpatina shell
+export EDITOR=vim
+export PAGER=less
+# END dotpatina shell
+alias ll="ls -l"
+"#
+        );
+    }
+
+    #[test]
+    fn test_apply_patina_blocks_in_one_target() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "block_patina.toml",
+            r#"
+                name = "shell"
+
+                [[files]]
+                template = "aliases"
+                target = ".bashrc"
+                block = true
+
+                [[files]]
+                template = "exports"
+                target = ".bashrc"
+                block = "exports"
+                mode = "0600"
+            "#,
+        );
+        tmp_dir.write_file("aliases", "alias ll=\"ls -l\"\n");
+        tmp_dir.write_file("exports", "export EDITOR=vim\n");
+        let target_path = tmp_dir.write_file(".bashrc", "# written by an installer\n");
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+        assert!(engine.apply_patina(false).is_ok());
+        let output = pi.get_all_output();
+        assert!(output.contains("managed block aliases"));
+        assert!(output.contains("managed block exports"));
+        assert_eq!(
+            fs::read_to_string(&target_path).unwrap(),
+            r#"# written by an installer
+
+# BEGIN dotpatina aliases
+alias ll="ls -l"
+# END dotpatina aliases
+
+# BEGIN dotpatina exports
+export EDITOR=vim
+# END dotpatina exports
+"#
+        );
+        #[cfg(unix)]
+        assert_eq!(get_file_mode(&target_path), Some(0o600));
+
+        // Change only the second block
+        tmp_dir.write_file("exports", "export EDITOR=nvim\n");
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+        assert!(engine.apply_patina(false).is_ok());
+        let output = pi.get_all_output();
+        assert!(output.contains("+   1 | export EDITOR=nvim"));
+        assert_eq!(
+            fs::read_to_string(&target_path).unwrap(),
+            r#"# written by an installer
+
+# BEGIN dotpatina aliases
+alias ll="ls -l"
+# END dotpatina aliases
+
+# BEGIN dotpatina exports
+export EDITOR=nvim
+# END dotpatina exports
+"#
+        );
+    }
+
+    #[test]
+    fn test_apply_patina_block_invalid_markers() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "block_patina.toml",
+            r#"
+                name = "shell"
+                description = "This is a Patina for a managed block"
+
+                [[files]]
+                template = "bashrc"
+                target = ".bashrc"
+                block = "shell"
+            "#,
+        );
+        tmp_dir.write_file("bashrc", "export EDITOR=vim\n");
+        let target_path = tmp_dir.write_file(".bashrc", "# BEGIN dotpatina shell\n");
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+
+        let apply = engine.apply_patina(false);
+
+        assert!(apply.is_err());
+        assert!(apply.unwrap_err().is_invalid_block());
+        assert_eq!(
+            fs::read_to_string(&target_path).unwrap(),
+            "# BEGIN dotpatina shell\n"
+        );
+    }
+
+    #[test]
+    fn test_apply_patina_merge() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "merge_patina.toml",
+            r#"
+                name = "merge-patina"
+                description = "This is a Patina for merged settings"
+
+                [vars]
+                font_size = 14
+
+                [[files]]
+                template = "settings.json.hbs"
+                target = "settings.json"
+                merge = "json"
+            "#,
+        );
+        tmp_dir.write_file(
+            "settings.json.hbs",
+            r#"{ "editor.fontSize": {{ font_size }}, "window.zoomLevel": null }"#,
+        );
+        let target_path = tmp_dir.write_file(
+            "settings.json",
+            r#"{ "editor.fontSize": 12, "window.zoomLevel": 1, "workbench.startupEditor": "none" }"#,
+        );
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+        assert!(engine.apply_patina(true).is_ok());
+        let output = pi.get_all_output();
+        assert!(output.contains("\"editor.fontSize\": 14"));
+        assert!(!output.contains("original files moved to trash"));
+        assert_eq!(
+            fs::read_to_string(&target_path).unwrap(),
+            r#"{
+  "editor.fontSize": 14,
+  "workbench.startupEditor": "none"
+}
+"#
+        );
+
+        // Apply again without changes
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+        assert!(engine.apply_patina(false).is_ok());
+        assert!(pi
+            .get_all_output()
+            .contains("No file changes detected in the patina"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_patina_hooks() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "hooks_patina.toml",
+            r#"
+                name = "hooks-patina"
+                description = "This is a Patina with hooks"
+
+                [hooks]
+                pre_apply = ["echo pre >> hooks.log"]
+                post_apply = ["echo post >> hooks.log"]
+
+                [[files]]
+                template = "a.txt"
+                target = "output/a.txt"
+                on_change = ["echo reload >> hooks.log"]
+
+                [[files]]
+                template = "b.txt"
+                target = "output/b.txt"
+                on_change = ["echo reload >> hooks.log", "echo b >> hooks.log"]
+            "#,
+        );
+        tmp_dir.write_file("a.txt", "a\n");
+        tmp_dir.write_file("b.txt", "b\n");
+        fs::create_dir_all(tmp_dir.get_file_path("output")).unwrap();
+        tmp_dir.write_file("output/b.txt", "b\n");
+        let log_path = tmp_dir.get_file_path("hooks.log");
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+        assert!(engine.apply_patina(false).is_ok());
+        assert!(pi
+            .get_all_output()
+            .contains("Running echo reload >> hooks.log"));
+        assert_eq!(
+            fs::read_to_string(&log_path).unwrap(),
+            "pre\nreload\npost\n"
+        );
+
+        // Hooks don't run without changes
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+        assert!(engine.apply_patina(false).is_ok());
+        assert_eq!(
+            fs::read_to_string(&log_path).unwrap(),
+            "pre\nreload\npost\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_patina_included_hooks() {
+        let tmp_dir = TmpTestDir::new();
+        fs::create_dir_all(tmp_dir.get_file_path("base/scripts")).unwrap();
+        tmp_dir.write_file(
+            "base/patina.toml",
+            r#"
+                name = "base"
+
+                [hooks]
+                pre_apply = ["sh scripts/hook.sh pre"]
+                post_apply = ["sh scripts/hook.sh post"]
+            "#,
+        );
+        tmp_dir.write_file("base/scripts/hook.sh", "echo \"base $1\" >> ../hooks.log\n");
+        let patina_path = tmp_dir.write_file(
+            "hooks_patina.toml",
+            r#"
+                name = "hooks-patina"
+                description = "This is a Patina that includes hooks"
+                include = ["base/patina.toml"]
+
+                [hooks]
+                post_apply = ["echo post >> hooks.log"]
+
+                [[files]]
+                template = "a.txt"
+                target = "output/a.txt"
+            "#,
+        );
+        tmp_dir.write_file("a.txt", "a\n");
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+
+        let apply = engine.apply_patina(false);
+
+        assert!(apply.is_ok());
+        assert_eq!(
+            fs::read_to_string(tmp_dir.get_file_path("hooks.log")).unwrap(),
+            "base pre\nbase post\npost\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_patina_pre_apply_hook_failure() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "hooks_patina.toml",
+            r#"
+                name = "hooks-patina"
+                description = "This is a Patina with a failing hook"
+
+                [hooks]
+                pre_apply = ["exit 1"]
+
+                [[files]]
+                template = "a.txt"
+                target = "output/a.txt"
+            "#,
+        );
+        tmp_dir.write_file("a.txt", "a\n");
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+
+        let apply = engine.apply_patina(false);
+
+        assert!(apply.is_err());
+        assert!(apply.unwrap_err().is_hook_failed());
+        assert!(!tmp_dir.get_file_path("output/a.txt").exists());
+    }
+
+    #[test]
+    fn test_apply_patina_absent() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "absent_patina.toml",
+            r#"
+                name = "absent-patina"
+                description = "This is a Patina with absent files"
+
+                [[absent]]
+                target = "output/old.txt"
+
+                [[absent]]
+                target = "output/missing.txt"
+
+                [[absent]]
+                target = "output/tagged.txt"
+                tags = ["other"]
+            "#,
+        );
+        fs::create_dir_all(tmp_dir.get_file_path("output")).unwrap();
+        tmp_dir.write_file("output/old.txt", "old line\n");
+        tmp_dir.write_file("output/tagged.txt", "tagged\n");
+
+        let pi = TestPatinaInterface::new();
+        let tags = Some(TagExpression::parse("!other").unwrap());
+        let engine = PatinaEngine::new(&pi, &patina_path, tags.clone(), vec![]);
+        assert!(engine.apply_patina(false).is_ok());
+
+        let output = pi.get_all_output();
+        assert!(output.contains("file deleted"));
+        assert!(output.contains("- 1   | old line"));
+        assert!(output.contains("(removed)"));
+        assert!(!output.contains("missing.txt"));
+        assert!(!tmp_dir.get_file_path("output/old.txt").exists());
+        assert!(tmp_dir.get_file_path("output/tagged.txt").exists());
+
+        // Nothing to do once the target is gone
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, tags, vec![]);
+        assert!(engine.apply_patina(false).is_ok());
+        assert!(pi
+            .get_all_output()
+            .contains("No file changes detected in the patina"));
+    }
+
+    #[test]
+    fn test_apply_patina_absent_target_of_file() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "absent_patina.toml",
+            r#"
+                name = "absent-patina"
+
+                [[files]]
+                template = "a.txt"
+                target = "output/a.txt"
+
+                [[absent]]
+                target = "output/a.txt"
+            "#,
+        );
+        tmp_dir.write_file("a.txt", "a\n");
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+        let result = engine.apply_patina(false);
+
+        assert!(result.is_err());
+        assert!(result.unwrap_err().is_message());
+        assert!(!tmp_dir.get_file_path("output/a.txt").exists());
+    }
+
+    #[test]
+    fn test_migrate_patina() {
+        let tmp_dir = TmpTestDir::new();
+        let patina_path = tmp_dir.write_file(
+            "patina.toml",
+            r#"# old patina
+name = "old-patina"
+
+[[files]]
+template = "zshrc"
+target = "output/.zshrc"
+"#,
+        );
+
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+        assert!(engine.migrate_patina().is_ok());
+        assert!(pi
+            .get_all_output()
+            .contains("Migrated from version 1 to version 2"));
+        assert_eq!(
+            fs::read_to_string(&patina_path).unwrap(),
+            r#"# old patina
+version = 2
+name = "old-patina"
+
+[[files]]
+template = "zshrc"
+target = "output/.zshrc"
+render = true
+"#
+        );
+
+        // Migrating again does nothing
+        let pi = TestPatinaInterface::new();
+        let engine = PatinaEngine::new(&pi, &patina_path, None, vec![]);
+        assert!(engine.migrate_patina().is_ok());
+        assert!(pi.get_all_output().contains("is already at version 2"));
+    }
 }
@@ -0,0 +1,121 @@
+//! Running hook commands for [super::PatinaEngine], with their output streamed through a [PatinaInterface]
+
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::mpsc;
+use std::thread;
+
+use colored::Colorize;
+use log::info;
+
+use super::interface::PatinaInterface;
+use crate::utils::{shell_command, Error, Result};
+
+/// Run a list of hook commands in order, stopping at the first one that fails
+pub fn run_hooks<PI: PatinaInterface>(pi: &PI, commands: &[String], dir: &Path) -> Result<()> {
+    commands
+        .iter()
+        .try_for_each(|command| run_hook(pi, command, dir))
+}
+
+/// Run a single hook command with the system shell from the given directory.
+/// Lines from stdout and stderr are output as they are written.
+fn run_hook<PI: PatinaInterface>(pi: &PI, command: &str, dir: &Path) -> Result<()> {
+    info!("running hook in {}: {}", dir.display(), command);
+    pi.output(format!("{} {}\n", "Running".blue(), command));
+
+    let mut child = match shell_command(command)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => return Err(Error::RunHook(command.to_string(), e)),
+    };
+
+    let (tx, rx) = mpsc::channel();
+    let readers = [
+        child
+            .stdout
+            .take()
+            .map(|r| Box::new(r) as Box<dyn Read + Send>),
+        child
+            .stderr
+            .take()
+            .map(|r| Box::new(r) as Box<dyn Read + Send>),
+    ];
+    for reader in readers.into_iter().flatten() {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(reader).lines().map_while(|l| l.ok()) {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(tx);
+
+    for line in rx {
+        pi.output(format!("  {}\n", line.bright_black()));
+    }
+
+    match child.wait() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(Error::HookFailed(command.to_string(), status.code())),
+        Err(e) => Err(Error::RunHook(command.to_string(), e)),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::engine::interface::test::TestPatinaInterface;
+    use crate::tests::test_utils::TmpTestDir;
+
+    #[test]
+    fn test_run_hooks() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file("marker.txt", "");
+        let pi = TestPatinaInterface::new();
+        let commands = vec![String::from("echo hello"), String::from("ls")];
+
+        let result = run_hooks(&pi, &commands, &tmp_dir.path);
+
+        assert!(result.is_ok());
+        let output = pi.get_all_output();
+        assert!(output.contains("Running echo hello\n  hello\n"));
+        assert!(output.contains("Running ls\n  marker.txt\n"));
+    }
+
+    #[test]
+    fn test_run_hooks_stderr() {
+        let tmp_dir = TmpTestDir::new();
+        let pi = TestPatinaInterface::new();
+        let commands = vec![String::from("echo oops >&2")];
+
+        let result = run_hooks(&pi, &commands, &tmp_dir.path);
+
+        assert!(result.is_ok());
+        assert!(pi.get_all_output().contains("  oops\n"));
+    }
+
+    #[test]
+    fn test_run_hooks_failure_stops() {
+        let tmp_dir = TmpTestDir::new();
+        let pi = TestPatinaInterface::new();
+        let commands = vec![String::from("exit 3"), String::from("echo unreachable")];
+
+        let result = run_hooks(&pi, &commands, &tmp_dir.path);
+
+        assert!(result.is_err());
+        assert_eq!(
+            result.unwrap_err().as_hook_failed(),
+            Some((&String::from("exit 3"), &Some(3)))
+        );
+        assert!(!pi.get_all_output().contains("unreachable"));
+    }
+}
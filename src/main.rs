@@ -91,12 +91,15 @@
 
 use cli::PatinaCli;
 
+mod block;
 mod cli;
 mod diff;
 mod engine;
+mod merge;
 mod patina;
 mod templating;
 mod utils;
+mod validation;
 
 /// Main entry point for the application.
 /// This launches the CLI interface.
@@ -0,0 +1,406 @@
+//! The merge module deep-merges rendered structured data into existing target files, so that keys written by other
+//! applications are kept.
+//!
+//! TOML targets keep their comments and formatting for the values that don't change. JSON and YAML targets keep the
+//! order of their keys, but are rewritten in the format's default style.
+
+use std::path::Path;
+
+use serde_json::{json, Map, Value};
+use toml_edit::{DocumentMut, Item};
+
+use crate::patina::format::{self, Format};
+use crate::patina::vars::merge_values;
+use crate::utils::{Error, Result};
+
+/// Merge a rendered template into the contents of a target file, both in the given format.
+/// Returns the new contents of the target, which are the current contents if the merge does not change any values.
+pub fn merge_into_target(
+    format: Format,
+    target_path: &Path,
+    target_str: &str,
+    template_path: &Path,
+    render_str: &str,
+) -> Result<String> {
+    let mut target: Value = match target_str.trim().is_empty() {
+        true => Value::Object(Default::default()),
+        false => format::parse_data_as(target_path, target_str, format)?,
+    };
+    let render: Value = format::parse_data_as(template_path, render_str, format)?;
+
+    let original = target.clone();
+    merge_values(&mut target, render);
+    if target == original {
+        return Ok(target_str.to_string());
+    }
+
+    match format {
+        Format::Toml => update_toml(target_path, target_str, &original, &target),
+        _ => format::to_data_string(target_path, &target, format),
+    }
+}
+
+/// Update a TOML document from its original values to the merged values,
+/// only replacing the parts of the document that changed
+fn update_toml(
+    target_path: &Path,
+    target_str: &str,
+    original: &Value,
+    merged: &Value,
+) -> Result<String> {
+    let (Value::Object(original), Value::Object(merged)) = (original, merged) else {
+        return format::to_data_string(target_path, merged, Format::Toml);
+    };
+    if target_str.trim().is_empty() {
+        return format::to_data_string(target_path, merged, Format::Toml);
+    }
+
+    let mut doc = match target_str.parse::<DocumentMut>() {
+        Ok(doc) => doc,
+        Err(e) => return Err(Error::Message(format!("{}: {}", target_path.display(), e))),
+    };
+    let mut next_position = max_table_position(doc.as_table()) + 1;
+    let root = doc.as_table_mut();
+    update_toml_table(
+        target_path,
+        root,
+        original,
+        merged,
+        false,
+        &mut next_position,
+    )?;
+    Ok(doc.to_string())
+}
+
+/// Update the keys of a TOML table from their original values to the merged values
+fn update_toml_table(
+    target_path: &Path,
+    table: &mut dyn toml_edit::TableLike,
+    original: &Map<String, Value>,
+    merged: &Map<String, Value>,
+    inline: bool,
+    next_position: &mut usize,
+) -> Result<()> {
+    for key in original.keys().filter(|k| !merged.contains_key(*k)) {
+        table.remove(key);
+    }
+
+    for (key, value) in merged {
+        let original_value = original.get(key);
+        if original_value == Some(value) {
+            continue;
+        }
+
+        match (table.get_mut(key), original_value, value) {
+            (Some(item), Some(Value::Object(original)), Value::Object(merged))
+                if item.is_table_like() =>
+            {
+                let inline = item.is_inline_table();
+                let table = item.as_table_like_mut().unwrap();
+                update_toml_table(target_path, table, original, merged, inline, next_position)?;
+            }
+            (Some(item), _, _) => {
+                let mut new_item = toml_item(target_path, key, value, item.is_value())?;
+                set_table_positions(&mut new_item, next_position);
+                if let (Some(value), Some(new_value)) = (item.as_value(), new_item.as_value_mut()) {
+                    *new_value.decor_mut() = value.decor().clone();
+                }
+                *item = new_item;
+            }
+            (None, _, _) => {
+                let mut new_item = toml_item(target_path, key, value, inline)?;
+                set_table_positions(&mut new_item, next_position);
+                table.insert(key, new_item);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Get the highest position of the tables in a TOML table, which orders them in the document
+fn max_table_position(table: &toml_edit::Table) -> usize {
+    let nested = table.iter().flat_map(|(_, item)| match item {
+        Item::Table(table) => vec![max_table_position(table)],
+        Item::ArrayOfTables(tables) => tables.iter().map(max_table_position).collect(),
+        _ => vec![],
+    });
+    nested.chain(table.position()).max().unwrap_or(0)
+}
+
+/// Set the positions of the tables in a new TOML item, so that they come after the existing tables in order,
+/// separated by a blank line
+fn set_table_positions(item: &mut Item, next_position: &mut usize) {
+    let mut set_position = |table: &mut toml_edit::Table| {
+        table.set_position(*next_position);
+        table.decor_mut().set_prefix("\n");
+        *next_position += 1;
+        for (_, item) in table.iter_mut() {
+            set_table_positions(item, next_position);
+        }
+    };
+    match item {
+        Item::Table(table) => set_position(table),
+        Item::ArrayOfTables(tables) => tables.iter_mut().for_each(set_position),
+        _ => {}
+    }
+}
+
+/// Create a TOML item for a value, either as an inline value or as a table
+fn toml_item(target_path: &Path, key: &str, value: &Value, inline: bool) -> Result<Item> {
+    let toml_str = format::to_data_string(target_path, &json!({ key: value }), Format::Toml)?;
+    let mut doc = match toml_str.parse::<DocumentMut>() {
+        Ok(doc) => doc,
+        Err(e) => {
+            return Err(Error::SerializeData(
+                target_path.to_path_buf(),
+                e.to_string(),
+            ))
+        }
+    };
+
+    let item = doc.remove(key).unwrap_or_default();
+    match inline {
+        true => Ok(item
+            .into_value()
+            .map(Item::Value)
+            .unwrap_or_else(|item| item)),
+        false => Ok(item),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_into_target_json() {
+        let target_str = r#"{
+            "editor.fontSize": 12,
+            "workbench.colorTheme": "Default Dark+",
+            "window.zoomLevel": 1
+        }"#;
+        let render_str = r#"{
+            "editor.fontSize": 14,
+            "window.zoomLevel": null,
+            "files.exclude": { "**/.git": true }
+        }"#;
+
+        let result = merge_into_target(
+            Format::Json,
+            Path::new("settings.json"),
+            target_str,
+            Path::new("settings.json.hbs"),
+            render_str,
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            r#"{
+  "editor.fontSize": 14,
+  "workbench.colorTheme": "Default Dark+",
+  "files.exclude": {
+    "**/.git": true
+  }
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_merge_into_target_toml() {
+        let target_str = "[font]\nsize = 12\nfamily = \"Menlo\"\n";
+        let render_str = "[font]\nsize = 14\n";
+
+        let result = merge_into_target(
+            Format::Toml,
+            Path::new("alacritty.toml"),
+            target_str,
+            Path::new("alacritty.toml.hbs"),
+            render_str,
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), "[font]\nsize = 14\nfamily = \"Menlo\"\n");
+    }
+
+    #[test]
+    fn test_merge_into_target_toml_keeps_formatting() {
+        let target_str = r#"# Alacritty config
+live_config_reload = true # reload on save
+
+[font]
+size   = 12 # points
+family = "Menlo"
+offset = { x = 0, y = 1 }
+
+[window]
+opacity = 0.9
+"#;
+        let render_str = r#"
+            live_config_reload = false
+
+            [font]
+            size = 14
+            offset = { y = 2 }
+
+            [[hints.enabled]]
+            regex = "https?://"
+        "#;
+
+        let result = merge_into_target(
+            Format::Toml,
+            Path::new("alacritty.toml"),
+            target_str,
+            Path::new("alacritty.toml.hbs"),
+            render_str,
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            r#"# Alacritty config
+live_config_reload = false # reload on save
+
+[font]
+size   = 14 # points
+family = "Menlo"
+offset = { x = 0, y = 2 }
+
+[window]
+opacity = 0.9
+
+[[hints.enabled]]
+regex = "https?://"
+"#
+        );
+    }
+
+    #[test]
+    fn test_merge_into_target_jsonc_comments() {
+        let target_str = r#"{
+            // VS Code settings
+            "editor.fontSize": 12
+        }"#;
+
+        let result = merge_into_target(
+            Format::Json,
+            Path::new("settings.json"),
+            target_str,
+            Path::new("settings.json.hbs"),
+            r#"{ "editor.fontSize": 14 }"#,
+        );
+
+        assert!(result.is_err());
+        assert!(result.unwrap_err().is_json_parse());
+    }
+
+    #[test]
+    fn test_merge_into_target_yaml() {
+        let target_str = "gui:\n  border: rounded\n  showCommandLog: true\n";
+        let render_str = "gui:\n  showCommandLog: false\n";
+
+        let result = merge_into_target(
+            Format::Yaml,
+            Path::new("config.yml"),
+            target_str,
+            Path::new("config.yml.hbs"),
+            render_str,
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            "gui:\n  border: rounded\n  showCommandLog: false\n"
+        );
+    }
+
+    #[test]
+    fn test_merge_into_target_removes_key_in_place() {
+        let json = merge_into_target(
+            Format::Json,
+            Path::new("settings.json"),
+            r#"{ "a": 1, "b": 2, "c": 3, "d": 4 }"#,
+            Path::new("settings.json.hbs"),
+            r#"{ "b": null }"#,
+        );
+        let yaml = merge_into_target(
+            Format::Yaml,
+            Path::new("config.yml"),
+            "a: 1\nb: 2\nc: 3\nd: 4\n",
+            Path::new("config.yml.hbs"),
+            "b: null\n",
+        );
+
+        assert_eq!(
+            json.unwrap(),
+            "{\n  \"a\": 1,\n  \"c\": 3,\n  \"d\": 4\n}\n"
+        );
+        assert_eq!(yaml.unwrap(), "a: 1\nc: 3\nd: 4\n");
+    }
+
+    #[test]
+    fn test_merge_into_target_empty_target() {
+        let result = merge_into_target(
+            Format::Json,
+            Path::new("settings.json"),
+            "",
+            Path::new("settings.json.hbs"),
+            r#"{ "editor.fontSize": 14 }"#,
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), "{\n  \"editor.fontSize\": 14\n}\n");
+    }
+
+    #[test]
+    fn test_merge_into_target_no_changes_keeps_formatting() {
+        let target_str = "{\"editor.fontSize\":14,\"window.zoomLevel\":1}";
+
+        let result = merge_into_target(
+            Format::Json,
+            Path::new("settings.json"),
+            target_str,
+            Path::new("settings.json.hbs"),
+            r#"{ "editor.fontSize": 14 }"#,
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), target_str);
+    }
+
+    #[test]
+    fn test_merge_into_target_invalid_target() {
+        let result = merge_into_target(
+            Format::Json,
+            Path::new("settings.json"),
+            "{ invalid",
+            Path::new("settings.json.hbs"),
+            "{}",
+        );
+
+        assert!(result.is_err());
+        let err = result.unwrap_err();
+        assert!(err.is_json_parse());
+        assert_eq!(err.as_json_parse().unwrap().0, Path::new("settings.json"));
+    }
+
+    #[test]
+    fn test_merge_into_target_invalid_render() {
+        let result = merge_into_target(
+            Format::Toml,
+            Path::new("alacritty.toml"),
+            "",
+            Path::new("alacritty.toml.hbs"),
+            "size = ",
+        );
+
+        assert!(result.is_err());
+        let err = result.unwrap_err();
+        assert!(err.is_toml_parse());
+        assert_eq!(
+            err.as_toml_parse().unwrap().0,
+            Path::new("alacritty.toml.hbs")
+        );
+    }
+}
@@ -1,18 +1,40 @@
 //! Core [Patina] data structure and functions.
 
+use std::collections::BTreeMap;
+use std::fs;
 use std::path::{Path, PathBuf};
 
+use absent::AbsentFile;
+use log::debug;
 use patina_file::PatinaFile;
+use profile::PatinaProfile;
 use serde::{Deserialize, Serialize};
+use tags::TagExpression;
+use vars_exec::VarsExec;
+use version::PatinaVersion;
 
-use crate::utils::{normalize_path, Error, Result};
+use crate::templating;
+use crate::utils::{normalize_path, normalize_target_path, Error, Result};
 
+pub mod absent;
+pub mod format;
+mod include;
 pub mod patina_file;
-mod vars;
+pub mod profile;
+pub mod secrets;
+pub mod tags;
+pub mod vars;
+pub mod vars_exec;
+pub mod version;
 
 /// A [Patina] describes a set of variables and templates that can be rendered to files.
 #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct Patina {
+    /// The version of the patina file schema. Files without a version are read as version 1,
+    /// and are migrated to the current version when they are loaded.
+    #[serde(default = "PatinaVersion::unversioned")]
+    pub version: PatinaVersion,
+
     /// The name of the Patina
     pub name: String,
 
@@ -20,68 +42,251 @@ pub struct Patina {
     #[serde(default)]
     pub description: String,
 
+    /// A list of other patina files to include in this Patina.
+    /// The files and vars of included patinas are merged into this one.
+    #[serde(default)]
+    pub include: Vec<PathBuf>,
+
     /// A map of variables that can be used in the templates
     #[serde(default)]
     pub vars: Option<serde_json::Value>,
 
+    /// Variables whose values are the output of shell commands.
+    /// The commands are run by [Patina::load_vars_exec], before any variables files are loaded.
+    #[serde(default)]
+    pub vars_exec: BTreeMap<String, VarsExec>,
+
+    /// Directories with host-specific variables files, which are loaded automatically before any explicit ones.
+    /// See [Patina::get_vars_dirs_files] for the files that are loaded.
+    #[serde(default)]
+    pub vars_dirs: Vec<PathBuf>,
+
     /// A list of files referencing templates and their target output paths
     #[serde(default)]
     pub files: Vec<PatinaFile>,
 
+    /// A list of target files that should be removed
+    #[serde(default)]
+    pub absent: Vec<AbsentFile>,
+
+    /// Named profiles of vars files and tags
+    #[serde(default)]
+    pub profiles: BTreeMap<String, PatinaProfile>,
+
+    /// Commands to run before and after applying the Patina
+    #[serde(default)]
+    pub hooks: PatinaHooks,
+
     /// The path to this patina
     #[serde(skip)]
     pub base_path: Option<PathBuf>,
 }
 
+/// [PatinaHooks] are shell commands that run when a Patina is applied.
+/// They only run when there are file changes, from the directory of the Patina file that defines them.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PatinaHooks {
+    /// Commands to run before any files are written
+    #[serde(default)]
+    pub pre_apply: Vec<PatinaHook>,
+
+    /// Commands to run after all files are written
+    #[serde(default)]
+    pub post_apply: Vec<PatinaHook>,
+}
+
+/// A [PatinaHook] is a single hook command, written as a string
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub struct PatinaHook {
+    /// The shell command to run
+    pub command: String,
+
+    /// The directory of the Patina file that defined this hook.
+    /// This is set for hooks from included patinas, which run from their own directory.
+    pub base_path: Option<PathBuf>,
+}
+
+impl From<String> for PatinaHook {
+    fn from(command: String) -> Self {
+        PatinaHook {
+            command,
+            base_path: None,
+        }
+    }
+}
+
+impl From<PatinaHook> for String {
+    fn from(hook: PatinaHook) -> Self {
+        hook.command
+    }
+}
+
 impl Patina {
-    /// Load a Patina from a TOML file
-    pub fn from_toml_file(toml_file_path: &PathBuf) -> Result<Patina> {
-        let toml_str = match std::fs::read_to_string(toml_file_path) {
-            Ok(toml_str) => toml_str,
-            Err(e) => return Err(Error::FileRead(toml_file_path.clone(), e)),
+    /// Load a Patina from a TOML, JSON, or YAML file, including any patinas it references
+    pub fn from_file(file_path: &PathBuf) -> Result<Patina> {
+        let mut patina = Patina::parse_file(file_path)?;
+
+        let mut include_stack = vec![normalize_path(file_path).unwrap_or(file_path.clone())];
+        patina.load_includes(&mut include_stack)?;
+
+        // Resolve the array directives in vars that weren't overlaid onto the vars of an included patina
+        if let Some(vars) = patina.vars.take() {
+            patina.overlay_vars(vars);
+        }
+
+        Ok(patina)
+    }
+
+    /// Parse a single Patina file without resolving its includes.
+    /// The file format is chosen from the file extension, and files from older versions are migrated.
+    fn parse_file(file_path: &Path) -> Result<Patina> {
+        let data = match fs::read_to_string(file_path) {
+            Ok(data) => data,
+            Err(e) => return Err(Error::FileRead(file_path.to_path_buf(), e)),
         };
 
-        let mut patina: Patina = match toml::from_str(&toml_str) {
+        let mut value = format::parse_value(file_path, &data)?;
+        let file_version = version::value_version(file_path, &value)?;
+        if file_version < version::CURRENT_VERSION {
+            debug!(
+                "migrating patina {:?} from version {}",
+                file_path, file_version
+            );
+            version::migrate_value(&mut value, file_version);
+        }
+        let mut patina: Patina = match serde_json::from_value(value) {
             Ok(patina) => patina,
-            Err(e) => return Err(Error::TomlParse(e)),
+            // Migrations only add keys, so the data is parsed again for an error with a line and column
+            Err(e) => {
+                let located = format::parse_data::<Patina>(file_path, &data).err();
+                return Err(located.unwrap_or(Error::PatinaData(file_path.to_path_buf(), e)));
+            }
         };
-
-        patina.base_path = Some(toml_file_path.parent().unwrap().to_path_buf());
+        patina.check_array_directives(file_path)?;
+        for file in &patina.files {
+            if let Some(message) = file.check_options() {
+                return Err(Error::InvalidPatinaFile(
+                    file_path.to_path_buf(),
+                    file.template.clone(),
+                    message.to_string(),
+                ));
+            }
+        }
+        patina.base_path = Some(file_path.parent().unwrap().to_path_buf());
+        patina.expand_template_globs()?;
 
         Ok(patina)
     }
 
+    /// Expand files with glob templates into a file for each match, so that they can be replaced by target.
+    /// Globs in templated paths are expanded when rendering instead, since their vars aren't loaded yet.
+    /// Globs without matches are kept, so that rendering warns about them and validation reports them.
+    fn expand_template_globs(&mut self) -> Result<()> {
+        let mut files = vec![];
+        for file in std::mem::take(&mut self.files) {
+            if !file.is_template_glob() || file.is_templated_path() {
+                files.push(file);
+                continue;
+            }
+            match templating::expand_patina_file_glob(self, &file)? {
+                matches if matches.is_empty() => files.push(file),
+                matches => files.extend(matches),
+            }
+        }
+        self.files = files;
+        Ok(())
+    }
+
     /// Get a path within the context of this Patina
     pub fn get_patina_path<P: AsRef<Path>>(&self, path: P) -> PathBuf {
-        let path = path.as_ref();
-        let path = normalize_path(path).unwrap_or(path.to_path_buf());
+        resolve_path(self.base_path.as_deref(), path)
+    }
 
-        if path.is_absolute() {
-            return path.to_path_buf();
-        }
+    /// Get the full path to the template of a [PatinaFile]
+    pub fn get_template_path(&self, patina_file: &PatinaFile) -> PathBuf {
+        let base_path = patina_file
+            .base_path
+            .as_deref()
+            .or(self.base_path.as_deref());
+        resolve_path(base_path, &patina_file.template)
+    }
 
-        let mut result = self
+    /// Get the full path to the target of a [PatinaFile].
+    /// A target that is a link is not followed.
+    pub fn get_target_path(&self, patina_file: &PatinaFile) -> PathBuf {
+        let base_path = patina_file
             .base_path
-            .as_ref()
-            .unwrap_or(&PathBuf::from("."))
-            .clone();
-        result.push(path);
+            .as_deref()
+            .or(self.base_path.as_deref());
+        resolve_target_path(base_path, &patina_file.target)
+    }
+
+    /// Get the directory that the paths of a [PatinaFile] are relative to.
+    /// Files from included patinas are relative to their own patina, rather than this one.
+    pub fn get_patina_file_base_path(&self, patina_file: &PatinaFile) -> PathBuf {
+        match &patina_file.base_path {
+            Some(base_path) => normalize_path(base_path).unwrap_or(base_path.clone()),
+            None => self.get_base_path(),
+        }
+    }
+
+    /// Get the directory that a [PatinaHook] runs from.
+    /// Hooks from included patinas run from the directory of their own patina, rather than this one.
+    pub fn get_hook_base_path(&self, hook: &PatinaHook) -> PathBuf {
+        match &hook.base_path {
+            Some(base_path) => normalize_path(base_path).unwrap_or(base_path.clone()),
+            None => self.get_base_path(),
+        }
+    }
 
-        normalize_path(&result).unwrap_or(result)
+    /// Get the directory that the paths of this Patina are relative to
+    pub fn get_base_path(&self) -> PathBuf {
+        let base_path = self.base_path.as_deref().unwrap_or(Path::new("."));
+        normalize_path(base_path).unwrap_or(base_path.to_path_buf())
     }
 
-    /// Get an iterator for all PatinaFiles that are tagged with any of the provided tags
-    pub fn files_for_tags(&self, tags: Option<Vec<String>>) -> impl Iterator<Item = &PatinaFile> {
+    /// Get an iterator for all PatinaFiles whose tags match the provided tag expression
+    pub fn files_for_tags(&self, tags: Option<TagExpression>) -> impl Iterator<Item = &PatinaFile> {
         self.files.iter().filter(move |f| match &tags {
-            Some(tags) => f.tags.iter().any(|t| tags.contains(t)),
+            Some(tags) => tags.matches(&f.tags),
             None => true,
         })
     }
 }
 
+/// Resolve a path relative to a base path, defaulting to the current directory
+fn resolve_path<P: AsRef<Path>>(base_path: Option<&Path>, path: P) -> PathBuf {
+    resolve_path_with(base_path, path.as_ref(), true)
+}
+
+/// Resolve a target path relative to a base path like [resolve_path], without following a link at the target itself
+fn resolve_target_path<P: AsRef<Path>>(base_path: Option<&Path>, path: P) -> PathBuf {
+    resolve_path_with(base_path, path.as_ref(), false)
+}
+
+/// Resolve a path relative to a base path, following a link at the path itself only if `follow_link` is set
+fn resolve_path_with(base_path: Option<&Path>, path: &Path, follow_link: bool) -> PathBuf {
+    let normalize = |path: &Path| match follow_link {
+        true => normalize_path(path),
+        false => normalize_target_path(path),
+    };
+    let path = normalize(path).unwrap_or(path.to_path_buf());
+
+    if path.is_absolute() {
+        return path.to_path_buf();
+    }
+
+    let mut result = base_path.unwrap_or(Path::new(".")).to_path_buf();
+    result.push(path);
+
+    normalize(&result).unwrap_or(result)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils::Error;
     use crate::{tests::test_utils::TmpTestDir, utils::tests::get_home_dir};
 
     #[test]
@@ -118,6 +323,38 @@ mod tests {
         assert_eq!(patina_file.target, PathBuf::from("./output/hello.txt"));
     }
 
+    #[test]
+    fn test_patina_deserialize_file_vars() {
+        let patina = r#"
+            name = "file-vars-patina"
+
+            [vars]
+            me.email = "me@home.com"
+
+            [[files]]
+            template = "gitconfig.hbs"
+            target = "~/.gitconfig"
+
+            [[files]]
+            template = "gitconfig.hbs"
+            target = "~/work/.gitconfig"
+
+            [files.vars]
+            me.email = "me@work.com"
+        "#;
+
+        let patina = toml::from_str::<Patina>(patina);
+        assert!(patina.is_ok());
+        let patina = patina.unwrap();
+
+        assert_eq!(patina.files.len(), 2);
+        assert_eq!(patina.files[0].vars, None);
+        assert_eq!(
+            patina.files[1].vars,
+            Some(serde_json::json!({ "me": { "email": "me@work.com" } }))
+        );
+    }
+
     #[test]
     fn test_patina_deserialize_name_missing() {
         let patina = r#"
@@ -227,7 +464,7 @@ mod tests {
             "#,
         );
 
-        let patina = Patina::from_toml_file(&path);
+        let patina = Patina::from_file(&path);
         assert!(patina.is_ok());
         let patina = patina.unwrap();
         assert_eq!(patina.name, "simple-patina");
@@ -236,7 +473,7 @@ mod tests {
     #[test]
     fn test_patina_from_toml_file_missing_file() {
         let path = PathBuf::from("this/file/does/not/exist.toml");
-        let patina = Patina::from_toml_file(&path);
+        let patina = Patina::from_file(&path);
 
         let err = match patina {
             Ok(_) => panic!("expected error"),
@@ -251,6 +488,32 @@ mod tests {
         assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
     }
 
+    #[test]
+    fn test_patina_from_toml_file_datetime_vars() {
+        let tmp_dir = TmpTestDir::new();
+        for (file_name, version) in [("v1.toml", ""), ("v2.toml", "version = 2")] {
+            let path = tmp_dir.write_file(
+                file_name,
+                format!(
+                    r#"
+                        {}
+                        name = "datetime-patina"
+
+                        [vars]
+                        date = 1979-05-27
+                        times = [07:32:00]
+                    "#,
+                    version
+                ),
+            );
+
+            let vars = Patina::from_file(&path).unwrap().vars.unwrap();
+
+            assert_eq!(vars["date"], "1979-05-27");
+            assert_eq!(vars["times"][0], "07:32:00");
+        }
+    }
+
     #[test]
     fn test_patina_from_toml_file_invalid_format() {
         let tmp_dir = TmpTestDir::new();
@@ -268,10 +531,158 @@ mod tests {
             "#,
         );
 
-        let patina = Patina::from_toml_file(&path);
+        let patina = Patina::from_file(&path);
+        assert!(patina.is_err());
+        let err = patina.unwrap_err();
+        assert!(matches!(err, Error::TomlParse(..)));
+    }
+
+    #[test]
+    fn test_patina_from_yaml_file() {
+        let tmp_dir = TmpTestDir::new();
+        let path = tmp_dir.write_file(
+            "patina.yaml",
+            r#"
+name: simple-patina
+description: This is a simple Patina example
+vars:
+  name: Patina
+files:
+  - template: ./templates/hello.txt
+    target: ./output/hello.txt
+    mode: "0644"
+"#,
+        );
+
+        let patina = Patina::from_file(&path);
+        assert!(patina.is_ok());
+        let patina = patina.unwrap();
+        assert_eq!(patina.name, "simple-patina");
+        assert_eq!(patina.vars, Some(serde_json::json!({ "name": "Patina" })));
+        assert_eq!(patina.files.len(), 1);
+        assert_eq!(patina.files[0].target, PathBuf::from("./output/hello.txt"));
+    }
+
+    #[test]
+    fn test_patina_from_json_file() {
+        let tmp_dir = TmpTestDir::new();
+        let path = tmp_dir.write_file(
+            "patina.json",
+            r#"{
+                "name": "simple-patina",
+                "vars": { "name": "Patina" },
+                "files": [
+                    { "template": "./templates/hello.txt", "target": "./output/hello.txt" }
+                ]
+            }"#,
+        );
+
+        let patina = Patina::from_file(&path);
+        assert!(patina.is_ok());
+        let patina = patina.unwrap();
+        assert_eq!(patina.name, "simple-patina");
+        assert_eq!(patina.files.len(), 1);
+    }
+
+    #[test]
+    fn test_patina_from_json_file_invalid_format() {
+        let tmp_dir = TmpTestDir::new();
+        let path = tmp_dir.write_file("patina.json", r#"{ "description": "no name" }"#);
+
+        let patina = Patina::from_file(&path);
         assert!(patina.is_err());
         let err = patina.unwrap_err();
-        assert!(matches!(err, Error::TomlParse(_)));
+        let (err_path, location, _) = err.as_json_parse().unwrap();
+        assert_eq!(err_path, &path);
+        assert!(location.is_some());
+    }
+
+    #[test]
+    fn test_patina_from_file_expands_template_globs() {
+        let tmp_dir = TmpTestDir::new();
+        fs::create_dir_all(tmp_dir.get_file_path("scripts")).unwrap();
+        tmp_dir.write_file("scripts/a.sh.hbs", "echo {{ a }}");
+        tmp_dir.write_file("scripts/b.sh", "echo b");
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "glob-patina"
+
+                [[files]]
+                template = "scripts/*.sh*"
+                target = "~/bin/"
+
+                [[files]]
+                template = "{{ dir }}/*.sh"
+                target = "~/{{ dir }}/"
+
+                [[files]]
+                template = "none/*.zz"
+                target = "~/zz/"
+            "#,
+        );
+
+        let patina = Patina::from_file(&path).unwrap();
+
+        let files: Vec<(&str, &str)> = patina
+            .files
+            .iter()
+            .map(|f| (f.template.to_str().unwrap(), f.target.to_str().unwrap()))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                ("scripts/a.sh.hbs", "~/bin/a.sh"),
+                ("scripts/b.sh", "~/bin/b.sh"),
+                ("{{ dir }}/*.sh", "~/{{ dir }}/"),
+                ("none/*.zz", "~/zz/"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_patina_from_file_invalid_template_glob() {
+        let tmp_dir = TmpTestDir::new();
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "glob-patina"
+
+                [[files]]
+                template = "scripts/[*.sh"
+                target = "~/bin/"
+            "#,
+        );
+
+        let patina = Patina::from_file(&path);
+
+        assert!(patina.is_err());
+        assert!(patina.unwrap_err().is_invalid_glob());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_patina_get_target_path_does_not_follow_link() {
+        let tmp_dir = TmpTestDir::new();
+        let file_path = tmp_dir.write_file("file.txt", "");
+        let link_path = tmp_dir.get_file_path("link.txt");
+        crate::utils::create_symlink(&file_path, &link_path).unwrap();
+        let patina = Patina {
+            base_path: Some(tmp_dir.path.clone()),
+            ..Default::default()
+        };
+
+        let patina_file = PatinaFile::new("link.txt", "link.txt");
+
+        let tmp_dir_path = normalize_path(&tmp_dir.path).unwrap();
+        assert_eq!(
+            patina.get_template_path(&patina_file),
+            tmp_dir_path.join("file.txt")
+        );
+        assert_eq!(
+            patina.get_target_path(&patina_file),
+            tmp_dir_path.join("link.txt")
+        );
     }
 
     #[test]
@@ -282,6 +693,7 @@ mod tests {
             base_path: Some(PathBuf::from("tests")),
             vars: None,
             files: vec![],
+            ..Default::default()
         };
 
         let result = patina.get_patina_path(PathBuf::from("~/.dotpatina/home-dir-test.txt"));
@@ -299,6 +711,7 @@ mod tests {
             base_path: Some(PathBuf::from("tests")),
             vars: None,
             files: vec![],
+            ..Default::default()
         };
 
         let result = patina.get_patina_path(PathBuf::from("/tmp/dotpatina/absolute-test.txt"));
@@ -313,6 +726,7 @@ mod tests {
             base_path: Some(PathBuf::from("tests")),
             vars: None,
             files: vec![],
+            ..Default::default()
         };
 
         let result = patina.get_patina_path(PathBuf::from("some-dir/test.txt"));
@@ -331,18 +745,22 @@ mod tests {
                     template: PathBuf::from("a.hbs"),
                     target: PathBuf::from("a.txt"),
                     tags: vec!["a".to_string()],
+                    ..Default::default()
                 },
                 PatinaFile {
                     template: PathBuf::from("b.hbs"),
                     target: PathBuf::from("b.txt"),
                     tags: vec!["b".to_string()],
+                    ..Default::default()
                 },
                 PatinaFile {
                     template: PathBuf::from("ab.hbs"),
                     target: PathBuf::from("ab.txt"),
                     tags: vec!["a".to_string(), "b".to_string()],
+                    ..Default::default()
                 },
             ],
+            ..Default::default()
         };
 
         let patina_file_a = &patina.files[0];
@@ -353,23 +771,105 @@ mod tests {
         let filter_none = patina.files_for_tags(tags).collect::<Vec<&PatinaFile>>();
         assert_eq!(filter_none.len(), 3);
 
-        let tags = Some(vec!["a".to_string()]);
+        let tags = Some(TagExpression::parse("a").unwrap());
         let filter_a: Vec<&PatinaFile> = patina.files_for_tags(tags).collect();
         assert_eq!(filter_a.len(), 2);
         assert_eq!(filter_a[0], patina_file_a);
         assert_eq!(filter_a[1], patina_file_ab);
 
-        let tags = Some(vec!["b".to_string()]);
+        let tags = Some(TagExpression::parse("b").unwrap());
         let filter_b: Vec<&PatinaFile> = patina.files_for_tags(tags).collect();
         assert_eq!(filter_b.len(), 2);
         assert_eq!(filter_b[0], patina_file_b);
         assert_eq!(filter_b[1], patina_file_ab);
 
-        let tags = Some(vec!["a".to_string(), "b".to_string()]);
+        let tags = Some(TagExpression::parse("a | b").unwrap());
         let filter_ab: Vec<&PatinaFile> = patina.files_for_tags(tags).collect();
         assert_eq!(filter_ab.len(), 3);
         assert_eq!(filter_ab[0], patina_file_a);
         assert_eq!(filter_ab[1], patina_file_b);
         assert_eq!(filter_ab[2], patina_file_ab);
+
+        let tags = Some(TagExpression::parse("a & b").unwrap());
+        let filter_a_and_b: Vec<&PatinaFile> = patina.files_for_tags(tags).collect();
+        assert_eq!(filter_a_and_b, vec![patina_file_ab]);
+
+        let tags = Some(TagExpression::parse("a & !b").unwrap());
+        let filter_a_not_b: Vec<&PatinaFile> = patina.files_for_tags(tags).collect();
+        assert_eq!(filter_a_not_b, vec![patina_file_a]);
+    }
+
+    #[test]
+    fn test_patina_from_file_migrates_old_version() {
+        let tmp_dir = TmpTestDir::new();
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "old-patina"
+
+                [[files]]
+                template = "zshrc"
+                target = "~/.zshrc"
+
+                [[files]]
+                template = "gitconfig.hbs"
+                target = "~/.gitconfig"
+            "#,
+        );
+
+        let patina = Patina::from_file(&path).unwrap();
+
+        assert_eq!(patina.version, PatinaVersion(version::CURRENT_VERSION));
+        assert_eq!(patina.files[0].render, Some(true));
+        assert_eq!(patina.files[1].render, None);
+    }
+
+    #[test]
+    fn test_patina_from_file_unsupported_version() {
+        let tmp_dir = TmpTestDir::new();
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                version = 99
+                name = "future-patina"
+            "#,
+        );
+
+        let patina = Patina::from_file(&path);
+
+        assert!(patina.is_err());
+        assert_eq!(
+            patina.unwrap_err().as_unsupported_version(),
+            Some((&path, &99))
+        );
+    }
+
+    #[test]
+    fn test_patina_from_file_invalid_file_options() {
+        let tmp_dir = TmpTestDir::new();
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "invalid-patina"
+
+                [[files]]
+                template = "bashrc"
+                target = ".bashrc"
+                link = true
+                block = true
+            "#,
+        );
+
+        let patina = Patina::from_file(&path);
+
+        assert!(patina.is_err());
+        assert_eq!(
+            patina.unwrap_err().as_invalid_patina_file(),
+            Some((
+                &path,
+                &PathBuf::from("bashrc"),
+                &String::from("a linked file can't be a managed block")
+            ))
+        );
     }
 }
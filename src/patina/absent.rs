@@ -0,0 +1,140 @@
+//! [AbsentFile] and related functionality
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::patina::tags::TagExpression;
+use crate::patina::Patina;
+
+/// An [AbsentFile] is a target that should not exist, like the config file of a retired application.
+/// Applying the Patina removes the target if it exists.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AbsentFile {
+    /// An optional list of tags for this absent file, used the same way as [super::patina_file::PatinaFile] tags
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// The path of the target file to remove
+    pub target: PathBuf,
+
+    /// The directory that the target is relative to, if it differs from the [Patina]'s.
+    /// This is set for absent files that come from included patinas.
+    #[serde(skip)]
+    pub base_path: Option<PathBuf>,
+}
+
+impl Patina {
+    /// Get an iterator for all AbsentFiles whose tags match the provided tag expression
+    pub fn absent_for_tags(
+        &self,
+        tags: Option<TagExpression>,
+    ) -> impl Iterator<Item = &AbsentFile> {
+        self.absent.iter().filter(move |a| match &tags {
+            Some(tags) => tags.matches(&a.tags),
+            None => true,
+        })
+    }
+
+    /// Get the full path to the target of an [AbsentFile]
+    pub fn get_absent_target_path(&self, absent_file: &AbsentFile) -> PathBuf {
+        match &absent_file.base_path {
+            Some(base_path) => super::resolve_target_path(Some(base_path), &absent_file.target),
+            None => super::resolve_target_path(self.base_path.as_deref(), &absent_file.target),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::utils::normalize_path;
+
+    #[test]
+    fn test_patina_deserialize_absent() {
+        let patina = r#"
+            name = "absent-patina"
+
+            [[absent]]
+            target = "~/.vimrc"
+            tags = ["vim"]
+
+            [[absent]]
+            target = "old/config"
+        "#;
+
+        let patina = toml::from_str::<Patina>(patina);
+
+        assert!(patina.is_ok());
+        let patina = patina.unwrap();
+        assert_eq!(patina.absent.len(), 2);
+        assert_eq!(patina.absent[0].target, PathBuf::from("~/.vimrc"));
+        assert_eq!(patina.absent[0].tags, vec!["vim"]);
+        assert_eq!(patina.absent[1].target, PathBuf::from("old/config"));
+        assert!(patina.absent[1].tags.is_empty());
+    }
+
+    #[test]
+    fn test_patina_absent_for_tags() {
+        let patina = Patina {
+            absent: vec![
+                AbsentFile {
+                    target: PathBuf::from("vimrc"),
+                    tags: vec![String::from("vim")],
+                    ..Default::default()
+                },
+                AbsentFile {
+                    target: PathBuf::from("zshrc"),
+                    tags: vec![String::from("shell")],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        let absent: Vec<&AbsentFile> = patina.absent_for_tags(None).collect();
+        assert_eq!(absent.len(), 2);
+
+        let tags = Some(TagExpression::parse("vim").unwrap());
+        let absent: Vec<&AbsentFile> = patina.absent_for_tags(tags).collect();
+        assert_eq!(absent, vec![&patina.absent[0]]);
+    }
+
+    #[test]
+    fn test_patina_get_absent_target_path() {
+        let patina = Patina {
+            base_path: Some(PathBuf::from("/patina")),
+            ..Default::default()
+        };
+
+        let absent_file = AbsentFile {
+            target: PathBuf::from("old/config"),
+            ..Default::default()
+        };
+        assert_eq!(
+            patina.get_absent_target_path(&absent_file),
+            PathBuf::from("/patina/old/config")
+        );
+
+        let absent_file = AbsentFile {
+            target: PathBuf::from("old/config"),
+            base_path: Some(PathBuf::from("/base")),
+            ..Default::default()
+        };
+        assert_eq!(
+            patina.get_absent_target_path(&absent_file),
+            PathBuf::from("/base/old/config")
+        );
+
+        let absent_file = AbsentFile {
+            target: PathBuf::from("~/.vimrc"),
+            ..Default::default()
+        };
+        assert_eq!(
+            patina.get_absent_target_path(&absent_file),
+            normalize_path("~/.vimrc").unwrap()
+        );
+    }
+}
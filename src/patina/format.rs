@@ -0,0 +1,280 @@
+//! Reading [super::Patina] data files in the supported formats.
+//! The format of a file is chosen from its extension: `.toml`, `.json`, or `.yaml`/`.yml`.
+
+use std::fs;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::utils::{Error, Location, Result};
+
+/// The data formats that patina and vars files can be written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// [TOML](https://toml.io)
+    Toml,
+
+    /// [JSON](https://www.json.org)
+    Json,
+
+    /// [YAML](https://yaml.org)
+    Yaml,
+}
+
+impl Format {
+    /// Get the format of a file from its extension. Files with other extensions are read as TOML.
+    pub fn from_path(path: &Path) -> Format {
+        let extension = path.extension().and_then(|e| e.to_str());
+        match extension.map(|e| e.to_lowercase()).as_deref() {
+            Some("json") => Format::Json,
+            Some("yaml") | Some("yml") => Format::Yaml,
+            _ => Format::Toml,
+        }
+    }
+}
+
+/// Read a data file from disk and parse it into a generic value, in the format given by its extension
+pub fn read_value_file(path: &Path) -> Result<Value> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) => return Err(Error::FileRead(path.to_path_buf(), e)),
+    };
+
+    parse_value(path, &data)
+}
+
+/// Parse data in the format given by the extension of the path it was read from
+pub fn parse_data<T: DeserializeOwned>(path: &Path, data: &str) -> Result<T> {
+    parse_data_as(path, data, Format::from_path(path))
+}
+
+/// Parse data in the given format. The path is only used for error messages.
+pub fn parse_data_as<T: DeserializeOwned>(path: &Path, data: &str, format: Format) -> Result<T> {
+    match format {
+        Format::Toml => toml::from_str(data).map_err(|e| toml_error(path, data, e)),
+        Format::Json => serde_json::from_str(data).map_err(|e| json_error(path, e)),
+        Format::Yaml => serde_yaml_ng::from_str(data).map_err(|e| yaml_error(path, e)),
+    }
+}
+
+/// Parse data into a generic value, in the format given by the extension of the path it was read from.
+/// TOML datetimes become strings, rather than the objects that serde uses to pass them through.
+pub fn parse_value(path: &Path, data: &str) -> Result<Value> {
+    match Format::from_path(path) {
+        Format::Toml => {
+            let table: toml::Table = toml::from_str(data).map_err(|e| toml_error(path, data, e))?;
+            Ok(toml_to_value(toml::Value::Table(table)))
+        }
+        format => parse_data_as(path, data, format),
+    }
+}
+
+/// Convert a TOML value to a generic value, with datetimes as strings
+pub fn toml_to_value(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Value::from(f),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(datetime) => Value::String(datetime.to_string()),
+        toml::Value::Array(array) => Value::Array(array.into_iter().map(toml_to_value).collect()),
+        toml::Value::Table(table) => Value::Object(
+            table
+                .into_iter()
+                .map(|(key, value)| (key, toml_to_value(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Serialize data to a string in the given format. The path is only used for error messages.
+pub fn to_data_string<T: Serialize>(path: &Path, data: &T, format: Format) -> Result<String> {
+    let result = match format {
+        Format::Toml => toml::to_string_pretty(data).map_err(|e| e.to_string()),
+        Format::Json => serde_json::to_string_pretty(data)
+            .map(|s| s + "\n")
+            .map_err(|e| e.to_string()),
+        Format::Yaml => serde_yaml_ng::to_string(data).map_err(|e| e.to_string()),
+    };
+    result.map_err(|e| Error::SerializeData(path.to_path_buf(), e))
+}
+
+/// Read a data file from disk and parse it, collecting the paths of any keys that are not recognized
+pub fn read_data_file_with_unknown_keys<T: DeserializeOwned>(
+    path: &Path,
+) -> Result<(T, Vec<String>)> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) => return Err(Error::FileRead(path.to_path_buf(), e)),
+    };
+
+    let mut unknown_keys = vec![];
+    let on_unknown_key = |key: serde_ignored::Path| unknown_keys.push(key.to_string());
+    let result = match Format::from_path(path) {
+        Format::Toml => {
+            let deserializer = toml::Deserializer::new(&data);
+            serde_ignored::deserialize(deserializer, on_unknown_key)
+                .map_err(|e| toml_error(path, &data, e))
+        }
+        Format::Json => {
+            let mut deserializer = serde_json::Deserializer::from_str(&data);
+            serde_ignored::deserialize(&mut deserializer, on_unknown_key)
+                .and_then(|result| deserializer.end().map(|_| result))
+                .map_err(|e| json_error(path, e))
+        }
+        Format::Yaml => {
+            let deserializer = serde_yaml_ng::Deserializer::from_str(&data);
+            serde_ignored::deserialize(deserializer, on_unknown_key)
+                .map_err(|e| yaml_error(path, e))
+        }
+    };
+
+    result.map(|result| (result, unknown_keys))
+}
+
+/// Create an [Error] for failing to parse TOML data
+fn toml_error(path: &Path, data: &str, e: toml::de::Error) -> Error {
+    let location = e.span().map(|span| Location::from_offset(data, span.start));
+    Error::TomlParse(path.to_path_buf(), location, Box::new(e))
+}
+
+/// Create an [Error] for failing to parse JSON data
+fn json_error(path: &Path, e: serde_json::Error) -> Error {
+    let location = match e.line() {
+        0 => None,
+        line => Some(Location {
+            line,
+            column: e.column(),
+        }),
+    };
+    Error::JsonParse(path.to_path_buf(), location, e)
+}
+
+/// Create an [Error] for failing to parse YAML data
+fn yaml_error(path: &Path, e: serde_yaml_ng::Error) -> Error {
+    let location = e.location().map(|l| Location {
+        line: l.line(),
+        column: l.column(),
+    });
+    Error::YamlParse(path.to_path_buf(), location, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(Format::from_path(Path::new("patina.toml")), Format::Toml);
+        assert_eq!(Format::from_path(Path::new("patina.json")), Format::Json);
+        assert_eq!(Format::from_path(Path::new("patina.yaml")), Format::Yaml);
+        assert_eq!(Format::from_path(Path::new("patina.yml")), Format::Yaml);
+        assert_eq!(Format::from_path(Path::new("patina.YML")), Format::Yaml);
+        assert_eq!(Format::from_path(Path::new("patina")), Format::Toml);
+    }
+
+    #[test]
+    fn test_parse_data() {
+        let expected = json!({ "me": { "name": "Patina User" } });
+
+        let toml = parse_data::<Value>(Path::new("vars.toml"), "me.name = \"Patina User\"");
+        assert_eq!(toml.unwrap(), expected);
+
+        let json = parse_data::<Value>(
+            Path::new("vars.json"),
+            r#"{ "me": { "name": "Patina User" } }"#,
+        );
+        assert_eq!(json.unwrap(), expected);
+
+        let yaml = parse_data::<Value>(Path::new("vars.yaml"), "me:\n  name: Patina User\n");
+        assert_eq!(yaml.unwrap(), expected);
+    }
+
+    #[test]
+    fn test_parse_data_toml_error() {
+        let result = parse_data::<Value>(Path::new("vars.toml"), "a = 1\nb = = 2\n");
+
+        let err = result.unwrap_err();
+        let (path, location, _) = err.as_toml_parse().unwrap();
+        assert_eq!(path, &PathBuf::from("vars.toml"));
+        assert_eq!(location.unwrap().line, 2);
+    }
+
+    #[test]
+    fn test_parse_data_json_error() {
+        let result = parse_data::<Value>(Path::new("vars.json"), "{\n  \"a\": 1,\n  \"b\": }\n");
+
+        let err = result.unwrap_err();
+        let (path, location, _) = err.as_json_parse().unwrap();
+        assert_eq!(path, &PathBuf::from("vars.json"));
+        assert_eq!(location.unwrap().line, 3);
+    }
+
+    #[test]
+    fn test_parse_data_yaml_error() {
+        let result = parse_data::<Value>(Path::new("vars.yml"), "a: 1\nb: [1, 2\n");
+
+        let err = result.unwrap_err();
+        let (path, location, _) = err.as_yaml_parse().unwrap();
+        assert_eq!(path, &PathBuf::from("vars.yml"));
+        assert!(location.is_some());
+    }
+
+    #[test]
+    fn test_parse_value_toml_datetimes() {
+        let value = parse_value(
+            Path::new("vars.toml"),
+            "date = 1979-05-27\ntimes = [07:32:00]\nnested = { at = 1979-05-27T07:32:00Z }\n",
+        );
+
+        assert_eq!(
+            value.unwrap(),
+            json!({
+                "date": "1979-05-27",
+                "times": ["07:32:00"],
+                "nested": { "at": "1979-05-27T07:32:00Z" },
+            })
+        );
+    }
+
+    #[test]
+    fn test_read_data_file_with_unknown_keys() {
+        use crate::patina::Patina;
+        use crate::tests::test_utils::TmpTestDir;
+
+        let tmp_dir = TmpTestDir::new();
+        let toml_path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "unknown-keys"
+                colour = "blue"
+
+                [[files]]
+                template = "a.txt"
+                target = "a.txt"
+                tag = ["a"]
+            "#,
+        );
+        let yaml_path = tmp_dir.write_file(
+            "patina.yml",
+            "name: unknown-keys\nfiles:\n  - template: a.txt\n    targt: a.txt\n",
+        );
+
+        let result = read_data_file_with_unknown_keys::<Patina>(&toml_path);
+        assert!(result.is_ok());
+        let (patina, unknown_keys) = result.unwrap();
+        assert_eq!(patina.name, "unknown-keys");
+        assert_eq!(unknown_keys, vec!["colour", "files.0.tag"]);
+
+        let result = read_data_file_with_unknown_keys::<Patina>(&yaml_path);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().is_yaml_parse());
+    }
+}
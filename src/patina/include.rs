@@ -0,0 +1,538 @@
+//! [Patina] composition by including other patina files.
+
+use std::path::PathBuf;
+
+use log::debug;
+
+use crate::patina::vars_exec::merge_vars_exec;
+use crate::patina::Patina;
+use crate::utils::{normalize_path, Error, Result};
+
+impl Patina {
+    /// Load all included patinas and merge them into this one.
+    ///
+    /// Included patinas are merged in order, and this patina is overlaid on top:
+    /// - vars are deep-merged, with this patina's vars taking precedence
+    /// - vars_exec commands are deep-merged, and run from the directory of the patina they were defined in
+    /// - vars dirs are combined, keeping paths relative to the patina they were defined in
+    /// - files keep paths relative to the patina they were defined in
+    /// - files in this patina replace included files with the same target
+    /// - absent files are combined, keeping paths relative to the patina they were defined in
+    /// - profiles in this patina replace included profiles with the same name
+    /// - hooks of included patinas run before this patina's hooks
+    ///
+    /// The `include_stack` holds the patinas currently being loaded and is used to detect cycles.
+    pub(super) fn load_includes(&mut self, include_stack: &mut Vec<PathBuf>) -> Result<()> {
+        if self.include.is_empty() {
+            return Ok(());
+        }
+
+        let own_vars = self.vars.take();
+        let own_files = std::mem::take(&mut self.files);
+        let own_profiles = std::mem::take(&mut self.profiles);
+        let own_hooks = std::mem::take(&mut self.hooks);
+        let own_absent = std::mem::take(&mut self.absent);
+        let own_vars_dirs = std::mem::take(&mut self.vars_dirs);
+        let own_vars_exec = std::mem::take(&mut self.vars_exec);
+
+        for include in self.include.clone() {
+            let include_path = self.get_patina_path(&include);
+            let include_path = normalize_path(&include_path).unwrap_or(include_path);
+            if include_stack.contains(&include_path) {
+                return Err(Error::IncludeCycle(include_path));
+            }
+
+            debug!("including patina: {:?}", include_path);
+            let mut included = Patina::parse_file(&include_path)?;
+            include_stack.push(include_path);
+            included.load_includes(include_stack)?;
+            include_stack.pop();
+            let included_base_path = Some(included.get_base_path());
+
+            if let Some(vars) = included.vars {
+                self.overlay_vars(vars);
+            }
+            let mut included_vars_exec = included.vars_exec;
+            for entry in included_vars_exec.values_mut() {
+                entry.set_base_path(&included_base_path);
+            }
+            merge_vars_exec(&mut self.vars_exec, included_vars_exec);
+            for vars_dir in &included.vars_dirs {
+                self.vars_dirs
+                    .push(super::resolve_path(included.base_path.as_deref(), vars_dir));
+            }
+            for mut file in included.files {
+                if file.base_path.is_none() {
+                    file.base_path = included.base_path.clone();
+                }
+                self.files.push(file);
+            }
+            for mut absent_file in included.absent {
+                if absent_file.base_path.is_none() {
+                    absent_file.base_path = included.base_path.clone();
+                }
+                self.absent.push(absent_file);
+            }
+            for (hooks, included_hooks) in [
+                (&mut self.hooks.pre_apply, included.hooks.pre_apply),
+                (&mut self.hooks.post_apply, included.hooks.post_apply),
+            ] {
+                for mut hook in included_hooks {
+                    if hook.base_path.is_none() {
+                        hook.base_path = included.base_path.clone();
+                    }
+                    hooks.push(hook);
+                }
+            }
+            for (name, mut profile) in included.profiles {
+                if profile.base_path.is_none() {
+                    profile.base_path = included.base_path.clone();
+                }
+                self.profiles.insert(name, profile);
+            }
+        }
+
+        self.profiles.extend(own_profiles);
+        self.absent.extend(own_absent);
+        self.vars_dirs.extend(own_vars_dirs);
+        merge_vars_exec(&mut self.vars_exec, own_vars_exec);
+        self.hooks.pre_apply.extend(own_hooks.pre_apply);
+        self.hooks.post_apply.extend(own_hooks.post_apply);
+
+        if let Some(vars) = own_vars {
+            self.overlay_vars(vars);
+        }
+        // Only included files are replaced, so that duplicate targets in this patina are left for validation
+        let mut is_included = vec![true; self.files.len()];
+        for file in own_files {
+            let target_path = self.get_target_path(&file);
+            match (0..is_included.len())
+                .find(|&i| is_included[i] && self.get_target_path(&self.files[i]) == target_path)
+            {
+                Some(i) => {
+                    self.files[i] = file;
+                    is_included[i] = false;
+                }
+                None => self.files.push(file),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use serde_json::json;
+
+    use crate::patina::{Patina, PatinaHook};
+    use crate::tests::test_utils::TmpTestDir;
+    use crate::utils::normalize_path;
+
+    #[test]
+    fn test_load_includes() {
+        let tmp_dir = TmpTestDir::new();
+        fs::create_dir_all(tmp_dir.get_file_path("base")).unwrap();
+        tmp_dir.write_file(
+            "base/patina.toml",
+            r#"
+                name = "base"
+
+                [vars]
+                me.name = "Base User"
+                me.email = "base@mail.com"
+
+                [[files]]
+                template = "a.txt.hbs"
+                target = "a.txt"
+            "#,
+        );
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "overlay"
+                include = ["base/patina.toml"]
+
+                [vars]
+                me.name = "Overlay User"
+
+                [[files]]
+                template = "b.txt.hbs"
+                target = "b.txt"
+            "#,
+        );
+
+        let patina = Patina::from_file(&path);
+        assert!(patina.is_ok());
+        let patina = patina.unwrap();
+
+        assert_eq!(patina.name, "overlay");
+        assert_eq!(
+            patina.vars,
+            Some(json!({
+                "me": {
+                    "name": "Overlay User",
+                    "email": "base@mail.com"
+                }
+            }))
+        );
+
+        assert_eq!(patina.files.len(), 2);
+        let base_dir = normalize_path(tmp_dir.get_file_path("base")).unwrap();
+        let tmp_dir_path = normalize_path(&tmp_dir.path).unwrap();
+        assert_eq!(
+            patina.get_template_path(&patina.files[0]),
+            base_dir.join("a.txt.hbs")
+        );
+        assert_eq!(
+            patina.get_target_path(&patina.files[0]),
+            base_dir.join("a.txt")
+        );
+        assert_eq!(
+            patina.get_template_path(&patina.files[1]),
+            tmp_dir_path.join("b.txt.hbs")
+        );
+    }
+
+    #[test]
+    fn test_load_includes_replaces_files_with_same_target() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file(
+            "base.toml",
+            r#"
+                name = "base"
+
+                [[files]]
+                template = "base-gitconfig.hbs"
+                target = "~/.gitconfig"
+
+                [[files]]
+                template = "zshrc"
+                target = "~/.zshrc"
+            "#,
+        );
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "overlay"
+                include = ["base.toml"]
+
+                [[files]]
+                template = "my-gitconfig.hbs"
+                target = "~/.gitconfig"
+            "#,
+        );
+
+        let patina = Patina::from_file(&path).unwrap();
+
+        assert_eq!(patina.files.len(), 2);
+        assert_eq!(patina.files[0].template.to_str(), Some("my-gitconfig.hbs"));
+        assert!(patina.files[0].base_path.is_none());
+        assert_eq!(patina.files[1].template.to_str(), Some("zshrc"));
+    }
+
+    #[test]
+    fn test_load_includes_keeps_own_files_with_same_target() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file(
+            "base.toml",
+            r#"
+                name = "base"
+
+                [[files]]
+                template = "base-gitconfig.hbs"
+                target = "~/.gitconfig"
+            "#,
+        );
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "overlay"
+                include = ["base.toml"]
+
+                [[files]]
+                template = "my-gitconfig.hbs"
+                target = "~/.gitconfig"
+
+                [[files]]
+                template = "other-gitconfig.hbs"
+                target = "~/.gitconfig"
+            "#,
+        );
+
+        let patina = Patina::from_file(&path).unwrap();
+
+        let templates: Vec<&str> = patina
+            .files
+            .iter()
+            .map(|f| f.template.to_str().unwrap())
+            .collect();
+        assert_eq!(templates, vec!["my-gitconfig.hbs", "other-gitconfig.hbs"]);
+    }
+
+    #[test]
+    fn test_load_includes_replaces_glob_matches_with_same_target() {
+        let tmp_dir = TmpTestDir::new();
+        fs::create_dir_all(tmp_dir.get_file_path("base/scripts")).unwrap();
+        tmp_dir.write_file("base/scripts/a.sh", "echo a");
+        tmp_dir.write_file("base/scripts/b.sh", "echo b");
+        tmp_dir.write_file(
+            "base/patina.toml",
+            r#"
+                name = "base"
+
+                [[files]]
+                template = "scripts/*.sh"
+                target = "~/bin/"
+            "#,
+        );
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "overlay"
+                include = ["base/patina.toml"]
+
+                [[files]]
+                template = "my-a.sh"
+                target = "~/bin/a.sh"
+            "#,
+        );
+
+        let patina = Patina::from_file(&path).unwrap();
+
+        assert_eq!(patina.files.len(), 2);
+        assert_eq!(patina.files[0].template.to_str(), Some("my-a.sh"));
+        assert!(patina.files[0].base_path.is_none());
+        assert_eq!(patina.files[1].template.to_str(), Some("scripts/b.sh"));
+        assert_eq!(patina.files[1].target.to_str(), Some("~/bin/b.sh"));
+    }
+
+    #[test]
+    fn test_load_includes_profiles() {
+        let tmp_dir = TmpTestDir::new();
+        fs::create_dir_all(tmp_dir.get_file_path("base")).unwrap();
+        tmp_dir.write_file(
+            "base/patina.toml",
+            r#"
+                name = "base"
+
+                [profiles.work]
+                vars_files = ["work.toml"]
+
+                [profiles.home]
+                vars_files = ["home.toml"]
+            "#,
+        );
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "overlay"
+                include = ["base/patina.toml"]
+
+                [profiles.home]
+                vars_files = ["my-home.toml"]
+            "#,
+        );
+
+        let patina = Patina::from_file(&path).unwrap();
+
+        let base_dir = normalize_path(tmp_dir.get_file_path("base")).unwrap();
+        let tmp_dir_path = normalize_path(&tmp_dir.path).unwrap();
+        assert_eq!(
+            patina.get_profile("work").unwrap().vars_files,
+            vec![base_dir.join("work.toml")]
+        );
+        assert_eq!(
+            patina.get_profile("home").unwrap().vars_files,
+            vec![tmp_dir_path.join("my-home.toml")]
+        );
+    }
+
+    #[test]
+    fn test_load_includes_vars_dirs() {
+        let tmp_dir = TmpTestDir::new();
+        fs::create_dir_all(tmp_dir.get_file_path("base")).unwrap();
+        tmp_dir.write_file(
+            "base/patina.toml",
+            r#"
+                name = "base"
+                vars_dirs = ["vars"]
+            "#,
+        );
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "overlay"
+                include = ["base/patina.toml"]
+                vars_dirs = ["my-vars"]
+            "#,
+        );
+
+        let patina = Patina::from_file(&path).unwrap();
+
+        let base_dir = normalize_path(tmp_dir.get_file_path("base")).unwrap();
+        assert_eq!(
+            patina.vars_dirs,
+            vec![base_dir.join("vars"), PathBuf::from("my-vars")]
+        );
+    }
+
+    #[test]
+    fn test_load_includes_nested() {
+        let tmp_dir = TmpTestDir::new();
+        fs::create_dir_all(tmp_dir.get_file_path("a/b")).unwrap();
+        tmp_dir.write_file(
+            "a/b/patina.toml",
+            r#"
+                name = "b"
+
+                [vars]
+                b = "bbb"
+            "#,
+        );
+        tmp_dir.write_file(
+            "a/patina.toml",
+            r#"
+                name = "a"
+                include = ["b/patina.toml"]
+
+                [vars]
+                a = "aaa"
+            "#,
+        );
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "root"
+                include = ["a/patina.toml"]
+            "#,
+        );
+
+        let patina = Patina::from_file(&path).unwrap();
+
+        assert_eq!(patina.vars, Some(json!({ "a": "aaa", "b": "bbb" })));
+    }
+
+    #[test]
+    fn test_load_includes_cycle() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file(
+            "a.toml",
+            r#"
+                name = "a"
+                include = ["b.toml"]
+            "#,
+        );
+        tmp_dir.write_file(
+            "b.toml",
+            r#"
+                name = "b"
+                include = ["a.toml"]
+            "#,
+        );
+
+        let patina = Patina::from_file(&tmp_dir.get_file_path("a.toml"));
+
+        assert!(patina.is_err());
+        assert!(patina.unwrap_err().is_include_cycle());
+    }
+
+    #[test]
+    fn test_load_includes_missing_file() {
+        let tmp_dir = TmpTestDir::new();
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "overlay"
+                include = ["does-not-exist.toml"]
+            "#,
+        );
+
+        let patina = Patina::from_file(&path);
+
+        assert!(patina.is_err());
+        assert!(patina.unwrap_err().is_file_read());
+    }
+
+    #[test]
+    fn test_load_includes_hooks() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file(
+            "base.toml",
+            r#"
+                name = "base"
+
+                [hooks]
+                post_apply = ["tmux source-file ~/.tmux.conf"]
+            "#,
+        );
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "overlay"
+                include = ["base.toml"]
+
+                [hooks]
+                pre_apply = ["mkdir -p ~/.cache"]
+                post_apply = ["fc-cache"]
+            "#,
+        );
+
+        let patina = Patina::from_file(&path).unwrap();
+
+        let commands = |hooks: &[PatinaHook]| -> Vec<String> {
+            hooks.iter().map(|h| h.command.clone()).collect()
+        };
+        assert_eq!(commands(&patina.hooks.pre_apply), vec!["mkdir -p ~/.cache"]);
+        assert_eq!(
+            commands(&patina.hooks.post_apply),
+            vec!["tmux source-file ~/.tmux.conf", "fc-cache"]
+        );
+        assert!(patina.hooks.pre_apply[0].base_path.is_none());
+        assert!(patina.hooks.post_apply[0].base_path.is_some());
+        assert!(patina.hooks.post_apply[1].base_path.is_none());
+    }
+
+    #[test]
+    fn test_load_includes_absent() {
+        let tmp_dir = TmpTestDir::new();
+        fs::create_dir_all(tmp_dir.get_file_path("base")).unwrap();
+        tmp_dir.write_file(
+            "base/patina.toml",
+            r#"
+                name = "base"
+
+                [[absent]]
+                target = "old.conf"
+            "#,
+        );
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "overlay"
+                include = ["base/patina.toml"]
+
+                [[absent]]
+                target = "other.conf"
+            "#,
+        );
+
+        let patina = Patina::from_file(&path).unwrap();
+
+        let targets: Vec<PathBuf> = patina
+            .absent
+            .iter()
+            .map(|a| patina.get_absent_target_path(a))
+            .collect();
+        assert_eq!(
+            targets,
+            vec![
+                tmp_dir.path.join("base/old.conf"),
+                tmp_dir.path.join("other.conf")
+            ]
+        );
+    }
+}
@@ -1,21 +1,200 @@
 //! [PatinaFile] and related functionality
 
-use std::path::PathBuf;
+use std::fmt;
+use std::path::{Path, PathBuf};
 
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::patina::format::Format;
+
+/// Characters that make a template path a glob pattern
+const GLOB_CHARS: [char; 3] = ['*', '?', '['];
+
+/// The extension of template files that are rendered with handlebars by default
+pub const TEMPLATE_EXTENSION: &str = "hbs";
 
 /// A [PatinaFile] describes a template file and its target output path.
-#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PatinaFile {
     /// An optional list of tags for this patina file. This allows subsets of files to be specified from the command line
     #[serde(default)]
     pub tags: Vec<String>,
 
-    /// The path to the template file
+    /// The path to the template file.
+    /// If this is a directory, every file under it is rendered to the same relative path under the target directory.
+    /// If this is a glob pattern, every match is rendered into the target directory with the same file name.
     pub template: PathBuf,
 
     /// The path to the garget output file
     pub target: PathBuf,
+
+    /// An optional condition for including this file, evaluated against the vars.
+    /// This is either a template like `"{{eq facts.os 'linux'}}"` or an expression like `"work"` or `"not work"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when: Option<String>,
+
+    /// An optional map of variables for this file only.
+    /// These are merged over the [super::Patina] variables when rendering this file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vars: Option<serde_json::Value>,
+
+    /// Whether to render the template with handlebars, or copy it verbatim.
+    /// If not set, only templates with a `.hbs` extension are rendered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub render: Option<bool>,
+
+    /// An optional data format to deep-merge the rendered template into the existing target file with.
+    /// Keys in the target that are not in the template are kept, and null values in the template remove keys.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merge: Option<Format>,
+
+    /// Whether to link the target to the template file, instead of writing a rendered copy.
+    /// This allows editing the target in place for raw, non-templated files.
+    #[serde(default)]
+    pub link: bool,
+
+    /// Optional unix permissions for the target file, written as an octal string like `"0755"`.
+    /// `"block"` manages a block in the target, the same as `block = true`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<FileMode>,
+
+    /// Whether to manage only a marked block inside the target, leaving the rest of it alone.
+    /// This is `true` to name the block after the template path, or the name of the block.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block: Option<ManagedBlock>,
+
+    /// Shell commands to run after this file is written, only when it changed.
+    /// Commands that are shared by several changed files run once.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub on_change: Vec<String>,
+
+    /// The directory that this file's paths are relative to, if it differs from the [super::Patina]'s.
+    /// This is set for files that come from included patinas.
+    #[serde(skip)]
+    pub base_path: Option<PathBuf>,
+}
+
+/// The mode of a target file, written as an octal string like `"0755"`, or `"block"`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileMode {
+    /// Unix permissions for the target file
+    Permissions(u32),
+
+    /// Only a block inside the target is managed, the same as `block = true`
+    Block,
+}
+
+impl fmt::Display for FileMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileMode::Permissions(bits) => write!(f, "{:04o}", bits),
+            FileMode::Block => write!(f, "block"),
+        }
+    }
+}
+
+impl Serialize for FileMode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for FileMode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mode = String::deserialize(deserializer)?;
+        if mode == "block" {
+            return Ok(FileMode::Block);
+        }
+        match u32::from_str_radix(mode.trim_start_matches("0o"), 8) {
+            Ok(bits) if bits <= 0o7777 => Ok(FileMode::Permissions(bits)),
+            _ => Err(de::Error::custom(format!(
+                "invalid file mode `{}`, expected an octal string like \"0755\"",
+                mode
+            ))),
+        }
+    }
+}
+
+/// The managed block of a [PatinaFile], between `# BEGIN dotpatina <name>` and `# END dotpatina <name>` markers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ManagedBlock {
+    /// Whether the file is a managed block named after its template path
+    Enabled(bool),
+
+    /// The name of the managed block
+    Named(String),
+}
+
+impl PatinaFile {
+    /// Whether the template path is a glob pattern that can match multiple files
+    pub fn is_template_glob(&self) -> bool {
+        self.template.to_string_lossy().contains(GLOB_CHARS)
+    }
+
+    /// Whether the template or target path is rendered with the file's vars
+    pub fn is_templated_path(&self) -> bool {
+        let is_templated = |path: &Path| path.to_string_lossy().contains("{{");
+        is_templated(&self.template) || is_templated(&self.target)
+    }
+
+    /// The permissions to set on the target file, if any
+    pub fn permissions(&self) -> Option<u32> {
+        match self.mode {
+            Some(FileMode::Permissions(bits)) => Some(bits),
+            Some(FileMode::Block) | None => None,
+        }
+    }
+
+    /// The name of the managed block inside the target file, if only a block is managed.
+    /// Blocks that aren't named are named after the template path.
+    pub fn block_name(&self) -> Option<String> {
+        match (&self.block, self.mode) {
+            (Some(ManagedBlock::Named(name)), _) => Some(name.clone()),
+            (Some(ManagedBlock::Enabled(true)), _) | (None, Some(FileMode::Block)) => {
+                Some(self.template.display().to_string())
+            }
+            (Some(ManagedBlock::Enabled(false)), _) | (None, _) => None,
+        }
+    }
+
+    /// Whether only a marked block inside the target file is managed
+    pub fn is_block(&self) -> bool {
+        self.block_name().is_some()
+    }
+
+    /// Check for options that can't be used together, returning a description of the first problem
+    pub fn check_options(&self) -> Option<&'static str> {
+        if self.link && self.is_block() {
+            return Some("a linked file can't be a managed block");
+        }
+        if self.merge.is_some() && self.link {
+            return Some("a merged file can't be linked");
+        }
+        if self.link && self.mode.is_some() {
+            return Some("a linked file can't set a mode");
+        }
+        if self.link && self.render == Some(true) {
+            return Some("a linked file can't be rendered");
+        }
+        if self.merge.is_some() && self.is_block() {
+            return Some("a merged file can't be a managed block");
+        }
+        None
+    }
+
+    /// Whether the template is rendered with handlebars.
+    /// Linked files are never rendered, and otherwise only `.hbs` templates are rendered unless `render` is set.
+    pub fn is_rendered(&self) -> bool {
+        if self.link {
+            return false;
+        }
+        self.render.unwrap_or_else(|| {
+            self.template
+                .extension()
+                .is_some_and(|e| e == TEMPLATE_EXTENSION)
+        })
+    }
 }
 
 #[cfg(test)]
@@ -32,6 +211,15 @@ mod tests {
                 template,
                 target,
                 tags: vec![],
+                when: None,
+                vars: None,
+                render: None,
+                merge: None,
+                link: false,
+                mode: None,
+                block: None,
+                on_change: vec![],
+                base_path: None,
             }
         }
 
@@ -67,4 +255,175 @@ mod tests {
         assert_eq!(patina_file.tags[1], "bbb");
         assert_eq!(patina_file.tags[2], "ccc");
     }
+
+    #[test]
+    fn test_patina_file_is_template_glob() {
+        assert!(!PatinaFile::new("scripts/run.sh", "~/bin/").is_template_glob());
+        assert!(PatinaFile::new("scripts/*.sh", "~/bin/").is_template_glob());
+        assert!(PatinaFile::new("scripts/run?.sh", "~/bin/").is_template_glob());
+        assert!(PatinaFile::new("scripts/[ab].sh", "~/bin/").is_template_glob());
+    }
+
+    #[test]
+    fn test_patina_file_deserialize_mode() {
+        let patina_file = toml::from_str::<PatinaFile>(
+            r#"
+                template = "run.sh"
+                target = "~/bin/run.sh"
+                mode = "0755"
+            "#,
+        );
+
+        assert!(patina_file.is_ok());
+        let patina_file = patina_file.unwrap();
+        assert_eq!(patina_file.mode, Some(FileMode::Permissions(0o755)));
+        assert_eq!(patina_file.permissions(), Some(0o755));
+    }
+
+    #[test]
+    fn test_patina_file_deserialize_block() {
+        let patina_file = toml::from_str::<PatinaFile>(
+            r#"
+                template = "bashrc.hbs"
+                target = "~/.bashrc"
+                block = true
+                mode = "0600"
+            "#,
+        );
+
+        assert!(patina_file.is_ok());
+        let patina_file = patina_file.unwrap();
+        assert_eq!(patina_file.block, Some(ManagedBlock::Enabled(true)));
+        assert_eq!(patina_file.block_name(), Some(String::from("bashrc.hbs")));
+        assert!(patina_file.is_block());
+        assert_eq!(patina_file.permissions(), Some(0o600));
+    }
+
+    #[test]
+    fn test_patina_file_deserialize_block_mode() {
+        let patina_file = toml::from_str::<PatinaFile>(
+            r#"
+                template = "bashrc.hbs"
+                target = "~/.bashrc"
+                mode = "block"
+            "#,
+        );
+
+        assert!(patina_file.is_ok());
+        let patina_file = patina_file.unwrap();
+        assert_eq!(patina_file.mode, Some(FileMode::Block));
+        assert_eq!(patina_file.block_name(), Some(String::from("bashrc.hbs")));
+        assert!(patina_file.is_block());
+        assert_eq!(patina_file.permissions(), None);
+    }
+
+    #[test]
+    fn test_patina_file_deserialize_named_block() {
+        let patina_file = toml::from_str::<PatinaFile>(
+            r#"
+                template = "bashrc.hbs"
+                target = "~/.bashrc"
+                block = "aliases"
+            "#,
+        );
+
+        assert!(patina_file.is_ok());
+        let patina_file = patina_file.unwrap();
+        assert_eq!(patina_file.block_name(), Some(String::from("aliases")));
+        assert!(patina_file.is_block());
+    }
+
+    #[test]
+    fn test_patina_file_deserialize_invalid_mode() {
+        for (mode, message) in [
+            (
+                "rwxr-xr-x",
+                "invalid file mode `rwxr-xr-x`, expected an octal string like \"0755\"",
+            ),
+            (
+                "0o17777",
+                "invalid file mode `0o17777`, expected an octal string like \"0755\"",
+            ),
+        ] {
+            let patina_file = toml::from_str::<PatinaFile>(&format!(
+                "template = \"run.sh\"\ntarget = \"~/bin/run.sh\"\nmode = \"{}\"",
+                mode
+            ));
+
+            assert!(patina_file.is_err());
+            assert_eq!(patina_file.unwrap_err().message(), message);
+        }
+    }
+
+    #[test]
+    fn test_file_mode_display() {
+        assert_eq!(FileMode::Permissions(0o755).to_string(), "0755");
+        assert_eq!(FileMode::Permissions(0o600).to_string(), "0600");
+        assert_eq!(FileMode::Block.to_string(), "block");
+    }
+
+    #[test]
+    fn test_patina_file_check_options() {
+        let mut patina_file = PatinaFile::new("bashrc", ".bashrc");
+        assert_eq!(patina_file.check_options(), None);
+
+        patina_file.block = Some(ManagedBlock::Enabled(false));
+        patina_file.link = true;
+        assert_eq!(patina_file.check_options(), None);
+
+        patina_file.block = Some(ManagedBlock::Enabled(true));
+        assert_eq!(
+            patina_file.check_options(),
+            Some("a linked file can't be a managed block")
+        );
+
+        patina_file.link = false;
+        patina_file.merge = Some(Format::Toml);
+        assert_eq!(
+            patina_file.check_options(),
+            Some("a merged file can't be a managed block")
+        );
+
+        patina_file.block = None;
+        patina_file.link = true;
+        assert_eq!(
+            patina_file.check_options(),
+            Some("a merged file can't be linked")
+        );
+
+        patina_file.merge = None;
+        patina_file.mode = Some(FileMode::Permissions(0o644));
+        assert_eq!(
+            patina_file.check_options(),
+            Some("a linked file can't set a mode")
+        );
+
+        patina_file.mode = None;
+        patina_file.render = Some(true);
+        assert_eq!(
+            patina_file.check_options(),
+            Some("a linked file can't be rendered")
+        );
+
+        patina_file.render = Some(false);
+        assert_eq!(patina_file.check_options(), None);
+    }
+
+    #[test]
+    fn test_patina_file_is_rendered() {
+        assert!(PatinaFile::new("gitconfig.hbs", "~/.gitconfig").is_rendered());
+        assert!(!PatinaFile::new("lazygit.yml", "~/lazygit.yml").is_rendered());
+
+        let mut patina_file = PatinaFile::new("gitconfig.hbs", "~/.gitconfig");
+        patina_file.render = Some(false);
+        assert!(!patina_file.is_rendered());
+
+        let mut patina_file = PatinaFile::new("gitconfig", "~/.gitconfig");
+        patina_file.render = Some(true);
+        assert!(patina_file.is_rendered());
+
+        let mut patina_file = PatinaFile::new("gitconfig.hbs", "~/.gitconfig");
+        patina_file.link = true;
+        assert!(!patina_file.is_rendered());
+    }
 }
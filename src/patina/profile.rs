@@ -0,0 +1,123 @@
+//! [PatinaProfile] and related functionality
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::patina::Patina;
+use crate::utils::{Error, Result};
+
+/// A [PatinaProfile] bundles a set of vars files and tags under a name, so they can be selected with `--profile`.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PatinaProfile {
+    /// A list of vars files to overlay, in order
+    #[serde(default)]
+    pub vars_files: Vec<PathBuf>,
+
+    /// A list of tags to filter on
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// The directory that this profile's paths are relative to, if it differs from the [Patina]'s.
+    /// This is set for profiles that come from included patinas.
+    #[serde(skip)]
+    pub base_path: Option<PathBuf>,
+}
+
+impl Patina {
+    /// Get a profile by name, with its vars files resolved within the context of this Patina
+    pub fn get_profile(&self, name: &str) -> Result<PatinaProfile> {
+        let Some(profile) = self.profiles.get(name) else {
+            return Err(Error::UnknownProfile(name.to_string()));
+        };
+
+        let vars_files = profile
+            .vars_files
+            .iter()
+            .map(|f| match &profile.base_path {
+                Some(base_path) => super::resolve_path(Some(base_path), f),
+                None => self.get_patina_path(f),
+            })
+            .collect();
+
+        Ok(PatinaProfile {
+            vars_files,
+            tags: profile.tags.clone(),
+            base_path: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::patina::Patina;
+
+    #[test]
+    fn test_patina_deserialize_profiles() {
+        let patina = r#"
+            name = "profiles-patina"
+
+            [profiles.work]
+            vars_files = ["vars/work.toml"]
+            tags = ["shell", "git"]
+
+            [profiles.home]
+            tags = ["shell"]
+        "#;
+
+        let patina = toml::from_str::<Patina>(patina);
+        assert!(patina.is_ok());
+        let patina = patina.unwrap();
+
+        assert_eq!(patina.profiles.len(), 2);
+        let work = &patina.profiles["work"];
+        assert_eq!(work.vars_files, vec![PathBuf::from("vars/work.toml")]);
+        assert_eq!(work.tags, vec!["shell", "git"]);
+        let home = &patina.profiles["home"];
+        assert!(home.vars_files.is_empty());
+        assert_eq!(home.tags, vec!["shell"]);
+    }
+
+    #[test]
+    fn test_get_profile() {
+        let mut patina = toml::from_str::<Patina>(
+            r#"
+                name = "profiles-patina"
+
+                [profiles.work]
+                vars_files = ["vars/a.toml", "vars/b.toml"]
+                tags = ["shell"]
+            "#,
+        )
+        .unwrap();
+        patina.base_path = Some(PathBuf::from("dotfiles"));
+
+        let profile = patina.get_profile("work");
+
+        assert!(profile.is_ok());
+        let profile = profile.unwrap();
+        assert_eq!(
+            profile.vars_files,
+            vec![
+                PathBuf::from("dotfiles/vars/a.toml"),
+                PathBuf::from("dotfiles/vars/b.toml")
+            ]
+        );
+        assert_eq!(profile.tags, vec!["shell"]);
+    }
+
+    #[test]
+    fn test_get_profile_unknown() {
+        let patina = toml::from_str::<Patina>(r#"name = "profiles-patina""#).unwrap();
+
+        let profile = patina.get_profile("work");
+
+        assert!(profile.is_err());
+        assert_eq!(
+            profile.unwrap_err().as_unknown_profile(),
+            Some(&String::from("work"))
+        );
+    }
+}
@@ -0,0 +1,412 @@
+//! Encrypted vars files, which keep secrets out of dotfiles repos in plaintext.
+//! Files are encrypted with [age](https://age-encryption.org), using the keys in an identity file or a passphrase.
+//!
+//! A vars file is encrypted if its name ends in `.age` or `.enc.toml`. The format of the decrypted contents is chosen
+//! from the rest of the name, so `secrets.yaml.age` holds YAML and `secrets.enc.toml` holds TOML.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use age::armor::{ArmoredReader, ArmoredWriter, Format};
+use age::secrecy::SecretString;
+use age::{Decryptor, Encryptor, IdentityFile, Recipient};
+use serde_json::Value;
+use uuid::Uuid;
+
+use super::format;
+use crate::utils::{Error, Result};
+
+/// The extension of age encrypted files
+pub const AGE_EXTENSION: &str = "age";
+
+/// The suffix of encrypted TOML files
+pub const ENCRYPTED_TOML_SUFFIX: &str = ".enc.toml";
+
+/// The key used to encrypt and decrypt vars files
+pub enum SecretKey {
+    /// An age identity file, like one created by `age-keygen`
+    Identity(PathBuf),
+
+    /// A passphrase
+    Passphrase(SecretString),
+}
+
+/// Whether a file is encrypted, based on its name
+pub fn is_encrypted(path: &Path) -> bool {
+    let is_age = path.extension().is_some_and(|e| e == AGE_EXTENSION);
+    let is_encrypted_toml = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(ENCRYPTED_TOML_SUFFIX));
+    is_age || is_encrypted_toml
+}
+
+/// Get the path that an encrypted file would have in plaintext, which decides the format of its contents
+pub fn plaintext_path(path: &Path) -> PathBuf {
+    match path.extension() {
+        Some(e) if e == AGE_EXTENSION => path.with_extension(""),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Read and decrypt an encrypted file
+pub fn read_encrypted_file(path: &Path, key: &SecretKey) -> Result<String> {
+    let ciphertext = match fs::read(path) {
+        Ok(ciphertext) => ciphertext,
+        Err(e) => return Err(Error::FileRead(path.to_path_buf(), e)),
+    };
+
+    decrypt(path, &ciphertext, key)
+}
+
+/// Decrypt the contents of an encrypted file, which can be binary or ASCII armored.
+/// The path is only used for error messages.
+pub fn decrypt(path: &Path, ciphertext: &[u8], key: &SecretKey) -> Result<String> {
+    let decrypt_error = |e| Error::Decrypt(path.to_path_buf(), Box::new(e));
+
+    let decryptor =
+        Decryptor::new_buffered(ArmoredReader::new(ciphertext)).map_err(decrypt_error)?;
+    let mut reader = match key {
+        SecretKey::Identity(identity_path) => {
+            let identities = read_identity_file(identity_path)?
+                .into_identities()
+                .map_err(|e| Error::Decrypt(identity_path.clone(), Box::new(e)))?;
+            decryptor.decrypt(identities.iter().map(|i| i.as_ref() as &dyn age::Identity))
+        }
+        SecretKey::Passphrase(passphrase) => {
+            let identity = age::scrypt::Identity::new(passphrase.clone());
+            decryptor.decrypt(std::iter::once(&identity as &dyn age::Identity))
+        }
+    }
+    .map_err(decrypt_error)?;
+
+    let mut plaintext = String::new();
+    if let Err(e) = reader.read_to_string(&mut plaintext) {
+        return Err(Error::FileRead(path.to_path_buf(), e));
+    }
+    Ok(plaintext)
+}
+
+/// Encrypt plaintext for an encrypted file, as ASCII armored text so that it can be diffed and reviewed.
+/// With an identity file, the text is encrypted to the recipients of its keys.
+/// The path is only used for error messages.
+pub fn encrypt(path: &Path, plaintext: &str, key: &SecretKey) -> Result<String> {
+    let encrypt_error = |e| Error::Encrypt(path.to_path_buf(), Box::new(e));
+
+    let encryptor = match key {
+        SecretKey::Identity(identity_path) => {
+            let recipients = read_identity_file(identity_path)?
+                .to_recipients()
+                .map_err(|e| Error::Encrypt(identity_path.clone(), Box::new(e)))?;
+            Encryptor::with_recipients(recipients.iter().map(|r| r.as_ref() as &dyn Recipient))
+                .map_err(encrypt_error)?
+        }
+        SecretKey::Passphrase(passphrase) => Encryptor::with_user_passphrase(passphrase.clone()),
+    };
+
+    let mut ciphertext = vec![];
+    let write_error = |e| Error::FileWrite(path.to_path_buf(), e);
+    let armored_writer =
+        ArmoredWriter::wrap_output(&mut ciphertext, Format::AsciiArmor).map_err(write_error)?;
+    let mut writer = encryptor.wrap_output(armored_writer).map_err(write_error)?;
+    writer
+        .write_all(plaintext.as_bytes())
+        .map_err(write_error)?;
+    writer
+        .finish()
+        .and_then(|armored_writer| armored_writer.finish())
+        .map_err(write_error)?;
+
+    Ok(String::from_utf8_lossy(&ciphertext).into_owned())
+}
+
+/// Encrypt a plaintext vars file, writing the encrypted file to `output`.
+/// The plaintext must be valid data in the format of its file extension.
+pub fn encrypt_file(path: &Path, output: &Path, key: &SecretKey) -> Result<()> {
+    let plaintext = match fs::read_to_string(path) {
+        Ok(plaintext) => plaintext,
+        Err(e) => return Err(Error::FileRead(path.to_path_buf(), e)),
+    };
+    format::parse_data::<Value>(path, &plaintext)?;
+
+    let ciphertext = encrypt(output, &plaintext, key)?;
+    match fs::write(output, ciphertext) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(Error::FileWrite(output.to_path_buf(), e)),
+    }
+}
+
+/// Edit an encrypted vars file by decrypting it to a temporary file and opening it with an editor.
+/// The editor is a command, and the path of the temporary file is added as its last argument.
+/// If the file does not exist yet, editing starts from an empty file.
+/// Returns whether the file was changed, which only happens if the edited text is valid data in its format.
+pub fn edit_encrypted_file(path: &Path, key: &SecretKey, editor: &str) -> Result<bool> {
+    let plaintext = match path.exists() {
+        true => read_encrypted_file(path, key)?,
+        false => String::new(),
+    };
+
+    // Keep the plaintext file name, so that editors can highlight its format
+    let file_name = plaintext_path(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let tmp_path = std::env::temp_dir().join(format!("dotpatina-{}-{}", Uuid::new_v4(), file_name));
+    write_private_file(&tmp_path, &plaintext)?;
+
+    let edited = run_editor(editor, &tmp_path).and_then(|()| match fs::read_to_string(&tmp_path) {
+        Ok(edited) => Ok(edited),
+        Err(e) => Err(Error::FileRead(tmp_path.clone(), e)),
+    });
+    let edited = match edited {
+        Ok(edited) => edited,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    };
+
+    if edited == plaintext {
+        let _ = fs::remove_file(&tmp_path);
+        return Ok(false);
+    }
+
+    // Invalid edits are kept, so that they can be fixed and encrypted again
+    if let Err(e) = format::parse_data::<Value>(&plaintext_path(path), &edited) {
+        return Err(Error::InvalidEdit(tmp_path, Box::new(e)));
+    }
+    let _ = fs::remove_file(&tmp_path);
+
+    let ciphertext = encrypt(path, &edited, key)?;
+    match fs::write(path, ciphertext) {
+        Ok(()) => Ok(true),
+        Err(e) => Err(Error::FileWrite(path.to_path_buf(), e)),
+    }
+}
+
+/// Write a file that only the current user can read
+fn write_private_file(path: &Path, contents: &str) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|e| Error::FileWrite(path.to_path_buf(), e))
+}
+
+/// Run an editor command on a file and wait for it to exit
+fn run_editor(editor: &str, path: &Path) -> Result<()> {
+    let mut args = editor.split_whitespace();
+    let Some(program) = args.next() else {
+        return Err(Error::Message(String::from("no editor is set")));
+    };
+
+    match Command::new(program).args(args).arg(path).status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(Error::Message(format!(
+            "editor `{}` exited with {}",
+            editor, status
+        ))),
+        Err(e) => Err(Error::Message(format!(
+            "failed to run editor `{}`: {}",
+            editor, e
+        ))),
+    }
+}
+
+/// Read an age identity file
+fn read_identity_file(identity_path: &Path) -> Result<IdentityFile<age::NoCallbacks>> {
+    let identity_file = identity_path
+        .to_str()
+        .map(|p| IdentityFile::from_file(p.to_string()));
+    match identity_file {
+        Some(Ok(identity_file)) => Ok(identity_file),
+        Some(Err(e)) => Err(Error::FileRead(identity_path.to_path_buf(), e)),
+        None => Err(Error::Message(format!(
+            "identity file path is not valid unicode: {}",
+            identity_path.display()
+        ))),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use age::secrecy::ExposeSecret;
+
+    use super::*;
+    use crate::tests::test_utils::TmpTestDir;
+
+    /// Write a new age identity file to a test directory
+    pub fn write_identity_file(tmp_dir: &TmpTestDir) -> PathBuf {
+        let identity = age::x25519::Identity::generate();
+        tmp_dir.write_file(
+            "identity.txt",
+            format!("{}\n", identity.to_string().expose_secret()),
+        )
+    }
+
+    #[test]
+    fn test_is_encrypted() {
+        assert!(is_encrypted(Path::new("secrets.toml.age")));
+        assert!(is_encrypted(Path::new("secrets.age")));
+        assert!(is_encrypted(Path::new("vars/secrets.enc.toml")));
+        assert!(!is_encrypted(Path::new("secrets.toml")));
+        assert!(!is_encrypted(Path::new("secrets.enc")));
+    }
+
+    #[test]
+    fn test_plaintext_path() {
+        assert_eq!(
+            plaintext_path(Path::new("secrets.yaml.age")),
+            PathBuf::from("secrets.yaml")
+        );
+        assert_eq!(
+            plaintext_path(Path::new("secrets.enc.toml")),
+            PathBuf::from("secrets.enc.toml")
+        );
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_identity() {
+        let tmp_dir = TmpTestDir::new();
+        let key = SecretKey::Identity(write_identity_file(&tmp_dir));
+        let path = Path::new("secrets.toml.age");
+
+        let ciphertext = encrypt(path, "token = \"secret\"\n", &key).unwrap();
+        assert!(ciphertext.starts_with("-----BEGIN AGE ENCRYPTED FILE-----"));
+        assert!(!ciphertext.contains("secret\""));
+
+        let plaintext = decrypt(path, ciphertext.as_bytes(), &key);
+        assert!(plaintext.is_ok());
+        assert_eq!(plaintext.unwrap(), "token = \"secret\"\n");
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_passphrase() {
+        let key = SecretKey::Passphrase(SecretString::from(String::from("correct horse")));
+        let path = Path::new("secrets.enc.toml");
+
+        let ciphertext = encrypt(path, "token = \"secret\"\n", &key).unwrap();
+        let plaintext = decrypt(path, ciphertext.as_bytes(), &key);
+        assert!(plaintext.is_ok());
+        assert_eq!(plaintext.unwrap(), "token = \"secret\"\n");
+
+        let wrong_key = SecretKey::Passphrase(SecretString::from(String::from("wrong")));
+        let plaintext = decrypt(path, ciphertext.as_bytes(), &wrong_key);
+        assert!(plaintext.is_err());
+        assert!(plaintext.unwrap_err().is_decrypt());
+    }
+
+    #[test]
+    fn test_decrypt_wrong_identity() {
+        let tmp_dir = TmpTestDir::new();
+        let key = SecretKey::Identity(write_identity_file(&tmp_dir));
+        let path = Path::new("secrets.toml.age");
+        let ciphertext = encrypt(path, "token = \"secret\"\n", &key).unwrap();
+
+        let other_dir = TmpTestDir::new();
+        let other_key = SecretKey::Identity(write_identity_file(&other_dir));
+        let plaintext = decrypt(path, ciphertext.as_bytes(), &other_key);
+
+        assert!(plaintext.is_err());
+        assert_eq!(
+            plaintext.unwrap_err().as_decrypt().unwrap().0,
+            &PathBuf::from("secrets.toml.age")
+        );
+    }
+
+    #[test]
+    fn test_encrypt_file() {
+        let tmp_dir = TmpTestDir::new();
+        let key = SecretKey::Identity(write_identity_file(&tmp_dir));
+        let path = tmp_dir.write_file("secrets.toml", "token = \"secret\"\n");
+        let output = tmp_dir.get_file_path("secrets.toml.age");
+
+        assert!(encrypt_file(&path, &output, &key).is_ok());
+
+        let plaintext = read_encrypted_file(&output, &key);
+        assert!(plaintext.is_ok());
+        assert_eq!(plaintext.unwrap(), "token = \"secret\"\n");
+    }
+
+    #[test]
+    fn test_encrypt_file_invalid_data() {
+        let tmp_dir = TmpTestDir::new();
+        let key = SecretKey::Identity(write_identity_file(&tmp_dir));
+        let path = tmp_dir.write_file("secrets.toml", "token = ");
+        let output = tmp_dir.get_file_path("secrets.toml.age");
+
+        let result = encrypt_file(&path, &output, &key);
+
+        assert!(result.is_err());
+        assert!(result.unwrap_err().is_toml_parse());
+        assert!(!output.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_edit_encrypted_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp_dir = TmpTestDir::new();
+        let key = SecretKey::Identity(write_identity_file(&tmp_dir));
+        let path = tmp_dir.get_file_path("secrets.enc.toml");
+        fs::write(&path, encrypt(&path, "a = 1\n", &key).unwrap()).unwrap();
+        let editor = tmp_dir.write_file("editor.sh", "#!/bin/sh\necho 'b = 2' >> \"$1\"\n");
+        fs::set_permissions(&editor, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let result = edit_encrypted_file(&path, &key, editor.to_str().unwrap());
+
+        assert!(result.is_ok());
+        assert!(result.unwrap());
+        assert_eq!(read_encrypted_file(&path, &key).unwrap(), "a = 1\nb = 2\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_edit_encrypted_file_invalid_data() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp_dir = TmpTestDir::new();
+        let key = SecretKey::Identity(write_identity_file(&tmp_dir));
+        let path = tmp_dir.get_file_path("secrets.enc.toml");
+        let ciphertext = encrypt(&path, "a = 1\n", &key).unwrap();
+        fs::write(&path, &ciphertext).unwrap();
+        let editor = tmp_dir.write_file("editor.sh", "#!/bin/sh\necho 'b = ' >> \"$1\"\n");
+        fs::set_permissions(&editor, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let result = edit_encrypted_file(&path, &key, editor.to_str().unwrap());
+
+        assert!(result.is_err());
+        let err = result.unwrap_err();
+        let (kept_path, e) = err.as_invalid_edit().unwrap();
+        assert!(e.is_toml_parse());
+        assert_eq!(fs::read_to_string(kept_path).unwrap(), "a = 1\nb = \n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), ciphertext);
+        fs::remove_file(kept_path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_edit_encrypted_file_without_changes() {
+        let tmp_dir = TmpTestDir::new();
+        let key = SecretKey::Identity(write_identity_file(&tmp_dir));
+        let path = tmp_dir.get_file_path("secrets.enc.toml");
+        let ciphertext = encrypt(&path, "a = 1\n", &key).unwrap();
+        fs::write(&path, &ciphertext).unwrap();
+
+        let result = edit_encrypted_file(&path, &key, "true");
+
+        assert!(result.is_ok());
+        assert!(!result.unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), ciphertext);
+    }
+}
@@ -0,0 +1,270 @@
+//! [TagExpression]s for filtering [super::patina_file::PatinaFile]s by their tags.
+//!
+//! An expression is made of tags combined with `&` (and), `|` (or), `!` (not), and parentheses.
+//! For example, `shell & !work` matches files tagged with `shell` that are not tagged with `work`.
+
+use crate::utils::{Error, Result};
+
+/// A boolean expression over tags
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagExpression {
+    /// Matches files with this tag
+    Tag(String),
+
+    /// Matches files that do not match the inner expression
+    Not(Box<TagExpression>),
+
+    /// Matches files that match both expressions
+    And(Box<TagExpression>, Box<TagExpression>),
+
+    /// Matches files that match either expression
+    Or(Box<TagExpression>, Box<TagExpression>),
+}
+
+/// A token in a tag expression
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Tag(String),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl TagExpression {
+    /// Parse a tag expression from a string
+    pub fn parse(expression: &str) -> Result<TagExpression> {
+        let tokens = tokenize(expression);
+        let mut parser = Parser {
+            expression,
+            tokens: &tokens,
+            position: 0,
+        };
+
+        let result = parser.parse_or()?;
+        match parser.peek() {
+            None => Ok(result),
+            Some((_, i)) => Err(parser.error(format!("unexpected `{}`", &expression[i..]))),
+        }
+    }
+
+    /// Build a single expression from the `--tags` and `--exclude-tags` command line arguments.
+    /// Files match if they match any of the tags expressions, and none of the exclude expressions.
+    pub fn from_args(tags: &[String], exclude_tags: &[String]) -> Result<Option<TagExpression>> {
+        let any_of = |expressions: &[String]| -> Result<Option<TagExpression>> {
+            let mut result: Option<TagExpression> = None;
+            for expression in expressions {
+                let expression = TagExpression::parse(expression)?;
+                result = Some(match result {
+                    Some(result) => TagExpression::Or(Box::new(result), Box::new(expression)),
+                    None => expression,
+                });
+            }
+            Ok(result)
+        };
+
+        let include = any_of(tags)?;
+        let exclude = any_of(exclude_tags)?.map(|e| TagExpression::Not(Box::new(e)));
+        Ok(match (include, exclude) {
+            (Some(include), Some(exclude)) => {
+                Some(TagExpression::And(Box::new(include), Box::new(exclude)))
+            }
+            (include, exclude) => include.or(exclude),
+        })
+    }
+
+    /// Determine whether a set of tags matches this expression
+    pub fn matches(&self, tags: &[String]) -> bool {
+        match self {
+            TagExpression::Tag(tag) => tags.contains(tag),
+            TagExpression::Not(e) => !e.matches(tags),
+            TagExpression::And(a, b) => a.matches(tags) && b.matches(tags),
+            TagExpression::Or(a, b) => a.matches(tags) || b.matches(tags),
+        }
+    }
+}
+
+/// Split an expression into tokens, along with the byte offset of each token
+fn tokenize(expression: &str) -> Vec<(Token, usize)> {
+    let mut tokens = vec![];
+    let mut chars = expression.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let token = match c {
+            '&' => Token::And,
+            '|' => Token::Or,
+            '!' => Token::Not,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            c if c.is_whitespace() => continue,
+            _ => {
+                let mut end = i + c.len_utf8();
+                while let Some((j, c)) = chars.peek() {
+                    if c.is_whitespace() || "&|!()".contains(*c) {
+                        break;
+                    }
+                    end = j + c.len_utf8();
+                    chars.next();
+                }
+                Token::Tag(expression[i..end].to_string())
+            }
+        };
+        tokens.push((token, i));
+    }
+    tokens
+}
+
+/// A recursive descent parser for tag expressions, where `!` binds tighter than `&`, and `&` binds tighter than `|`
+struct Parser<'a> {
+    expression: &'a str,
+    tokens: &'a [(Token, usize)],
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<(&'a Token, usize)> {
+        self.tokens.get(self.position).map(|(t, i)| (t, *i))
+    }
+
+    fn error(&self, message: String) -> Error {
+        Error::InvalidTagExpression(self.expression.to_string(), message)
+    }
+
+    fn parse_or(&mut self) -> Result<TagExpression> {
+        let mut result = self.parse_and()?;
+        while let Some((Token::Or, _)) = self.peek() {
+            self.position += 1;
+            result = TagExpression::Or(Box::new(result), Box::new(self.parse_and()?));
+        }
+        Ok(result)
+    }
+
+    fn parse_and(&mut self) -> Result<TagExpression> {
+        let mut result = self.parse_not()?;
+        while let Some((Token::And, _)) = self.peek() {
+            self.position += 1;
+            result = TagExpression::And(Box::new(result), Box::new(self.parse_not()?));
+        }
+        Ok(result)
+    }
+
+    fn parse_not(&mut self) -> Result<TagExpression> {
+        let Some((token, i)) = self.peek() else {
+            return Err(self.error(String::from("expected a tag at the end of the expression")));
+        };
+        self.position += 1;
+
+        match token {
+            Token::Tag(tag) => Ok(TagExpression::Tag(tag.clone())),
+            Token::Not => Ok(TagExpression::Not(Box::new(self.parse_not()?))),
+            Token::Open => {
+                let result = self.parse_or()?;
+                match self.peek() {
+                    Some((Token::Close, _)) => {
+                        self.position += 1;
+                        Ok(result)
+                    }
+                    _ => Err(self.error(format!("unclosed `(` at position {}", i + 1))),
+                }
+            }
+            _ => Err(self.error(format!("expected a tag at position {}", i + 1))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(tag: &str) -> Box<TagExpression> {
+        Box::new(TagExpression::Tag(tag.to_string()))
+    }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn test_tag_expression_parse() {
+        assert_eq!(TagExpression::parse("shell").unwrap(), *tag("shell"));
+        assert_eq!(
+            TagExpression::parse("shell & !work").unwrap(),
+            TagExpression::And(tag("shell"), Box::new(TagExpression::Not(tag("work"))))
+        );
+        assert_eq!(
+            TagExpression::parse("a | b & c").unwrap(),
+            TagExpression::Or(tag("a"), Box::new(TagExpression::And(tag("b"), tag("c"))))
+        );
+        assert_eq!(
+            TagExpression::parse("(a | b) & c").unwrap(),
+            TagExpression::And(Box::new(TagExpression::Or(tag("a"), tag("b"))), tag("c"))
+        );
+        assert_eq!(
+            TagExpression::parse("git-config").unwrap(),
+            *tag("git-config")
+        );
+    }
+
+    #[test]
+    fn test_tag_expression_parse_errors() {
+        let cases = [
+            ("", "expected a tag at the end of the expression"),
+            ("shell &", "expected a tag at the end of the expression"),
+            ("& shell", "expected a tag at position 1"),
+            ("(shell | git", "unclosed `(` at position 1"),
+            ("shell git", "unexpected `git`"),
+            ("shell)", "unexpected `)`"),
+        ];
+
+        for (expression, message) in cases {
+            let err = TagExpression::parse(expression).unwrap_err();
+            assert_eq!(
+                err.as_invalid_tag_expression(),
+                Some((&expression.to_string(), &message.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_tag_expression_matches() {
+        let expression = TagExpression::parse("shell & !work").unwrap();
+
+        assert!(expression.matches(&tags(&["shell"])));
+        assert!(expression.matches(&tags(&["shell", "home"])));
+        assert!(!expression.matches(&tags(&["shell", "work"])));
+        assert!(!expression.matches(&tags(&["git"])));
+        assert!(!expression.matches(&tags(&[])));
+    }
+
+    #[test]
+    fn test_tag_expression_from_args() {
+        assert_eq!(TagExpression::from_args(&[], &[]).unwrap(), None);
+
+        let expression = TagExpression::from_args(&tags(&["shell", "git"]), &[])
+            .unwrap()
+            .unwrap();
+        assert_eq!(expression, TagExpression::Or(tag("shell"), tag("git")));
+
+        let expression = TagExpression::from_args(&[], &tags(&["work"]))
+            .unwrap()
+            .unwrap();
+        assert!(expression.matches(&tags(&[])));
+        assert!(!expression.matches(&tags(&["work"])));
+
+        let expression = TagExpression::from_args(&tags(&["shell", "git"]), &tags(&["work"]))
+            .unwrap()
+            .unwrap();
+        assert!(expression.matches(&tags(&["git"])));
+        assert!(!expression.matches(&tags(&["git", "work"])));
+        assert!(!expression.matches(&tags(&["vim"])));
+    }
+
+    #[test]
+    fn test_tag_expression_from_args_invalid() {
+        let result = TagExpression::from_args(&tags(&["shell &"]), &[]);
+        assert!(result.unwrap_err().is_invalid_tag_expression());
+
+        let result = TagExpression::from_args(&[], &tags(&["(work"]));
+        assert!(result.unwrap_err().is_invalid_tag_expression());
+    }
+}
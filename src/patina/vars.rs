@@ -1,65 +1,370 @@
 //! [Patina] utilities for managing variables.
 
+use crate::patina::format;
+use crate::patina::patina_file::PatinaFile;
+use crate::patina::secrets::{self, SecretKey};
 use crate::patina::Patina;
+use crate::templating::facts::get_facts;
 use crate::utils::{Error, Result};
-use log::debug;
-use serde_json::Value;
-use std::path::PathBuf;
-
-/// Overlay the contents of source onto target as json maps recursively
-fn merge_values(a: &mut Value, b: Value) {
-    if let Value::Object(a) = a {
-        if let Value::Object(b) = b {
-            for (k, v) in b {
-                if v.is_null() {
-                    a.remove(&k);
-                } else {
-                    merge_values(a.entry(k).or_insert(Value::Null), v);
+use log::{debug, warn};
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+/// Overlay the contents of source onto target as json maps recursively.
+/// A null value in source removes the key from target, and an [ArrayDirective] changes the array in target.
+/// Maps in source are merged into an empty map when target is not a map, so that their directives are resolved.
+pub fn merge_values(a: &mut Value, b: Value) {
+    if let Some(directive) = ArrayDirective::parse(&b) {
+        directive.apply(a);
+        return;
+    }
+
+    let Value::Object(b) = b else {
+        *a = b;
+        return;
+    };
+    if !a.is_object() {
+        *a = Value::Object(Map::new());
+    }
+    let Value::Object(a) = a else {
+        return;
+    };
+    for (k, v) in b {
+        if v.is_null() {
+            a.shift_remove(&k);
+        } else {
+            merge_values(a.entry(k).or_insert(Value::Null), v);
+        }
+    }
+}
+
+/// Check that every map in a value whose keys start with `+` is a valid [ArrayDirective].
+/// Errors name the source of the value and the key path of the invalid directive.
+pub fn check_array_directives(value: &Value, source: &str, key_path: &str) -> Result<()> {
+    match value {
+        Value::Object(object) => {
+            if let Err(message) = ArrayDirective::try_parse(value) {
+                let location = match key_path.is_empty() {
+                    true => source.to_string(),
+                    false => format!("{}: {}", source, key_path),
+                };
+                return Err(Error::InvalidArrayDirective(location, message));
+            }
+            for (k, v) in object {
+                let key_path = match key_path.is_empty() {
+                    true => k.clone(),
+                    false => format!("{}.{}", key_path, k),
+                };
+                check_array_directives(v, source, &key_path)?;
+            }
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                check_array_directives(item, source, &format!("{}[{}]", key_path, i))?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// A directive for overlaying an array onto an existing one, instead of replacing it.
+/// Directives are written as an object with a single directive key, like `{ "+append" = [...] }`.
+#[derive(Debug, PartialEq)]
+enum ArrayDirective {
+    /// `+append`: add the items to the end of the array
+    Append(Vec<Value>),
+
+    /// `+prepend`: add the items to the start of the array
+    Prepend(Vec<Value>),
+
+    /// `+union`: add the items that aren't already in the array to the end of it
+    Union(Vec<Value>),
+
+    /// `+replace`: replace the array with the items
+    Replace(Vec<Value>),
+
+    /// `+merge`: deep-merge the items into the array items that have the same value for the `+key` field,
+    /// adding the items that don't match any. Without a `+key`, items are merged by their position.
+    Merge {
+        key: Option<String>,
+        items: Vec<Value>,
+    },
+}
+
+impl ArrayDirective {
+    /// Parse an [ArrayDirective] from a value, if it is a valid one
+    fn parse(value: &Value) -> Option<ArrayDirective> {
+        ArrayDirective::try_parse(value).ok().flatten()
+    }
+
+    /// Parse an [ArrayDirective] from a value.
+    /// Returns `None` for values without `+` keys, and a description of the problem for invalid directives.
+    /// A single value is treated as an array with one item.
+    fn try_parse(value: &Value) -> std::result::Result<Option<ArrayDirective>, String> {
+        let Some(object) = value.as_object() else {
+            return Ok(None);
+        };
+        if !object.keys().any(|k| k.starts_with('+')) {
+            return Ok(None);
+        }
+
+        let mut key = None;
+        let mut directive: Option<(&str, Vec<Value>)> = None;
+        for (k, v) in object {
+            let items = match v {
+                Value::Array(items) => items.clone(),
+                item => vec![item.clone()],
+            };
+            match k.as_str() {
+                "+key" => match v.as_str() {
+                    Some(v) => key = Some(v.to_string()),
+                    None => return Err(String::from("`+key` must be a string")),
+                },
+                "+append" | "+prepend" | "+union" | "+replace" | "+merge" => {
+                    if let Some((other, _)) = directive {
+                        return Err(format!("`{}` and `{}` can't be used together", other, k));
+                    }
+                    directive = Some((k.as_str(), items));
+                }
+                k if k.starts_with('+') => {
+                    return Err(format!("unknown array directive `{}`", k));
                 }
+                k => return Err(format!("`{}` can't be set next to an array directive", k)),
             }
-            return;
+        }
+
+        let key_error = || String::from("`+key` can only be used with `+merge`");
+        match (directive, key) {
+            (Some(("+merge", items)), key) => Ok(Some(ArrayDirective::Merge { key, items })),
+            (None, _) | (Some(_), Some(_)) => Err(key_error()),
+            (Some(("+append", items)), None) => Ok(Some(ArrayDirective::Append(items))),
+            (Some(("+prepend", items)), None) => Ok(Some(ArrayDirective::Prepend(items))),
+            (Some(("+union", items)), None) => Ok(Some(ArrayDirective::Union(items))),
+            (Some((_, items)), None) => Ok(Some(ArrayDirective::Replace(items))),
         }
     }
 
-    *a = b;
+    /// Apply the directive to the target value.
+    /// A target that isn't an array is treated as an empty array.
+    fn apply(self, target: &mut Value) {
+        let mut array = match target.take() {
+            Value::Array(array) => array,
+            _ => vec![],
+        };
+
+        match self {
+            ArrayDirective::Append(items) => array.extend(items.into_iter().map(resolve_value)),
+            ArrayDirective::Prepend(items) => {
+                array.splice(0..0, items.into_iter().map(resolve_value));
+            }
+            ArrayDirective::Union(items) => {
+                for item in items.into_iter().map(resolve_value) {
+                    if !array.contains(&item) {
+                        array.push(item);
+                    }
+                }
+            }
+            ArrayDirective::Replace(items) => {
+                array = items.into_iter().map(resolve_value).collect()
+            }
+            ArrayDirective::Merge { key: None, items } => {
+                for (i, item) in items.into_iter().enumerate() {
+                    match array.get_mut(i) {
+                        Some(existing) => merge_values(existing, item),
+                        None => array.push(resolve_value(item)),
+                    }
+                }
+            }
+            ArrayDirective::Merge {
+                key: Some(key),
+                items,
+            } => {
+                for item in items {
+                    let id = item.get(&key).cloned();
+                    let existing =
+                        id.and_then(|id| array.iter_mut().find(|a| a.get(&key) == Some(&id)));
+                    match existing {
+                        Some(existing) => merge_values(existing, item),
+                        None => array.push(resolve_value(item)),
+                    }
+                }
+            }
+        }
+
+        *target = Value::Array(array);
+    }
+}
+
+/// Resolve any array directives within a value that isn't being merged onto anything
+fn resolve_value(value: Value) -> Value {
+    let mut resolved = Value::Null;
+    merge_values(&mut resolved, value);
+    resolved
+}
+
+/// Parse a `key.path=value` variable into a nested vars object.
+/// The value is parsed as a TOML value, falling back to a string, and `null` is a null that removes the variable.
+pub fn parse_set_var(set_var: &str) -> Result<Value> {
+    let invalid = |message: &str| Error::InvalidSetVar(set_var.to_string(), message.to_string());
+
+    let Some((key_path, value_str)) = set_var.split_once('=') else {
+        return Err(invalid("expected `key=value`"));
+    };
+    let keys: Vec<&str> = key_path.trim().split('.').map(str::trim).collect();
+    if keys.iter().any(|k| k.is_empty()) {
+        return Err(invalid("the key path has an empty key"));
+    }
+
+    let value_str = value_str.trim();
+    let mut value = match value_str {
+        "null" => Value::Null,
+        _ => match toml::from_str::<toml::Table>(&format!("value = {}", value_str)) {
+            Ok(mut table) => match table.remove("value") {
+                Some(toml_value) => format::toml_to_value(toml_value),
+                None => Value::String(value_str.to_string()),
+            },
+            Err(_) => Value::String(value_str.to_string()),
+        },
+    };
+
+    for key in keys.into_iter().rev() {
+        let mut object = serde_json::Map::new();
+        object.insert(key.to_string(), value);
+        value = Value::Object(object);
+    }
+    check_array_directives(&value, &format!("--set {}", set_var), "")?;
+    Ok(value)
 }
 
 impl Patina {
-    /// Load vars files from disk and overlay them onto the current vars in order
-    pub fn load_vars_files(&mut self, vars_files: Vec<PathBuf>) -> Result<()> {
+    /// Parse `key.path=value` variables and overlay them onto the current vars in order
+    pub fn load_set_vars(&mut self, set_vars: &[String]) -> Result<()> {
+        for set_var in set_vars {
+            let vars = parse_set_var(set_var)?;
+            debug!("overlaying vars from --set {}", set_var);
+            self.overlay_vars(vars);
+        }
+        Ok(())
+    }
+
+    /// Get the variables files that exist in the `vars_dirs`, in the order they should be overlaid.
+    ///
+    /// Each of `default.toml`, `<os>.toml`, `<hostname>.toml`, and `local.toml` is looked for in every vars dir,
+    /// so that more specific files always take precedence, and later vars dirs take precedence over earlier ones.
+    pub fn get_vars_dirs_files(&self) -> Vec<PathBuf> {
+        if self.vars_dirs.is_empty() {
+            return vec![];
+        }
+
+        let facts = get_facts();
+        let os = facts["os"].as_str().unwrap_or_default();
+        let hostname = facts["hostname"].as_str().unwrap_or_default();
+        let names = ["default", os, hostname, "local"];
+        let mut vars_files = vec![];
+        for name in names.into_iter().filter(|n| !n.is_empty()) {
+            for vars_dir in &self.vars_dirs {
+                let vars_file = self
+                    .get_patina_path(vars_dir)
+                    .join(format!("{}.toml", name));
+                if vars_file.is_file() && !vars_files.contains(&vars_file) {
+                    // Logged as a warning so that it shows with `-v`, since vars_dirs files are found implicitly
+                    warn!("using vars file from vars_dirs: {}", vars_file.display());
+                    vars_files.push(vars_file);
+                }
+            }
+        }
         vars_files
-            .iter()
-            .try_for_each(|f| self.overlay_vars_from_file(f))
     }
 
-    /// Overlay the contents of vars_file onto the current vars
-    fn overlay_vars_from_file(&mut self, vars_file: &PathBuf) -> Result<()> {
-        let vars_str = match std::fs::read_to_string(vars_file) {
-            Ok(vars_str) => vars_str,
-            Err(e) => return Err(Error::FileRead(vars_file.clone(), e)),
-        };
+    /// Load vars files from disk and overlay them onto the current vars in order.
+    /// Encrypted vars files are decrypted with the secret key.
+    pub fn load_vars_files(
+        &mut self,
+        vars_files: Vec<PathBuf>,
+        secret_key: Option<&SecretKey>,
+    ) -> Result<()> {
+        vars_files
+            .iter()
+            .try_for_each(|f| self.overlay_vars_from_file(f, secret_key))
+    }
 
-        let vars: serde_json::Value = match toml::from_str(&vars_str) {
-            Ok(vars) => vars,
-            Err(e) => return Err(Error::TomlParse(e)),
+    /// Overlay the contents of vars_file onto the current vars.
+    /// The file format is chosen from the file extension.
+    fn overlay_vars_from_file(
+        &mut self,
+        vars_file: &PathBuf,
+        secret_key: Option<&SecretKey>,
+    ) -> Result<()> {
+        let vars: Value = match secrets::is_encrypted(vars_file) {
+            true => {
+                let Some(secret_key) = secret_key else {
+                    return Err(Error::MissingSecretKey(vars_file.clone()));
+                };
+                let data = secrets::read_encrypted_file(vars_file, secret_key)?;
+                debug!("overlaying vars from encrypted file: {:?}", vars_file);
+                format::parse_value(&secrets::plaintext_path(vars_file), &data)?
+            }
+            false => {
+                let vars = format::read_value_file(vars_file)?;
+                debug!("overlaying vars from file: {:?}, \n{:#?}", vars_file, vars);
+                vars
+            }
         };
 
-        debug!("overlaying vars from file: {:?}, \n{:#?}", vars_file, vars);
+        check_array_directives(&vars, &vars_file.display().to_string(), "")?;
+        self.overlay_vars(vars);
+        Ok(())
+    }
 
-        match self.vars {
-            Some(ref mut self_vars) => merge_values(self_vars, vars),
-            None => self.vars = Some(vars),
+    /// Check that the array directives in the vars of this Patina and its files are valid
+    pub(super) fn check_array_directives(&self, file_path: &Path) -> Result<()> {
+        let source = file_path.display().to_string();
+        if let Some(vars) = &self.vars {
+            check_array_directives(vars, &source, "vars")?;
+        }
+        for (i, file) in self.files.iter().enumerate() {
+            if let Some(vars) = &file.vars {
+                check_array_directives(vars, &source, &format!("files[{}].vars", i))?;
+            }
         }
         Ok(())
     }
+
+    /// Get the vars for rendering a [PatinaFile], with the file's own vars merged over the Patina vars
+    pub fn get_file_vars(&self, patina_file: &PatinaFile) -> Cow<'_, Option<Value>> {
+        let Some(file_vars) = &patina_file.vars else {
+            return Cow::Borrowed(&self.vars);
+        };
+
+        let mut vars = self
+            .vars
+            .clone()
+            .unwrap_or(Value::Object(Default::default()));
+        merge_values(&mut vars, file_vars.clone());
+        Cow::Owned(Some(vars))
+    }
+
+    /// Overlay vars onto the current vars
+    pub(super) fn overlay_vars(&mut self, vars: Value) {
+        let self_vars = self
+            .vars
+            .get_or_insert_with(|| Value::Object(Default::default()));
+        merge_values(self_vars, vars);
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::patina::vars::merge_values;
+    use crate::patina::patina_file::PatinaFile;
+    use crate::patina::secrets::{self, tests::write_identity_file, SecretKey};
+    use crate::patina::vars::{check_array_directives, merge_values, parse_set_var};
     use crate::patina::Patina;
+    use crate::templating::facts::get_facts;
     use crate::tests::test_utils::TmpTestDir;
     use serde_json::json;
+    use std::fs;
     use std::path::PathBuf;
 
     #[test]
@@ -108,6 +413,338 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_get_file_vars() {
+        let mut patina_file = PatinaFile::new("gitconfig.hbs", "work.gitconfig");
+        patina_file.vars = Some(json!({
+            "me": {
+                "email": "me@work.com"
+            }
+        }));
+        let patina = Patina {
+            vars: Some(json!({
+                "me": {
+                    "name": "Patina User",
+                    "email": "me@home.com"
+                }
+            })),
+            files: vec![patina_file],
+            ..Default::default()
+        };
+
+        let vars = patina.get_file_vars(&patina.files[0]);
+
+        assert_eq!(
+            *vars,
+            Some(json!({
+                "me": {
+                    "name": "Patina User",
+                    "email": "me@work.com"
+                }
+            }))
+        );
+        assert_eq!(
+            patina.vars,
+            Some(json!({
+                "me": {
+                    "name": "Patina User",
+                    "email": "me@home.com"
+                }
+            }))
+        );
+    }
+
+    #[test]
+    fn test_get_file_vars_without_file_vars() {
+        let patina = Patina {
+            vars: Some(json!({ "name": "Patina" })),
+            files: vec![PatinaFile::new("a.hbs", "a.txt")],
+            ..Default::default()
+        };
+
+        let vars = patina.get_file_vars(&patina.files[0]);
+
+        assert_eq!(*vars, Some(json!({ "name": "Patina" })));
+    }
+
+    #[test]
+    fn test_merge_values_array_directives() {
+        let mut a = json!({
+            "append": ["a", "b"],
+            "prepend": ["a", "b"],
+            "union": ["a", "b"],
+            "replace": ["a", "b"],
+            "single": ["a"],
+            "replaced": ["a"],
+        });
+        let b = json!({
+            "append": { "+append": ["b", "c"] },
+            "prepend": { "+prepend": ["b", "c"] },
+            "union": { "+union": ["b", "c", "c"] },
+            "replace": { "+replace": ["c"] },
+            "single": { "+append": "b" },
+            "new": { "+append": ["a"] },
+            "replaced": ["b"],
+        });
+
+        merge_values(&mut a, b);
+
+        assert_eq!(
+            a,
+            json!({
+                "append": ["a", "b", "b", "c"],
+                "prepend": ["b", "c", "a", "b"],
+                "union": ["a", "b", "c"],
+                "replace": ["c"],
+                "single": ["a", "b"],
+                "new": ["a"],
+                "replaced": ["b"],
+            })
+        );
+    }
+
+    #[test]
+    fn test_merge_values_array_directive_merge() {
+        let mut a = json!({
+            "by_key": [
+                { "name": "home", "host": "home.lan", "port": 22 },
+                { "name": "work", "host": "work.com" },
+            ],
+            "by_position": [{ "a": "a" }, { "b": "b" }],
+        });
+        let b = json!({
+            "by_key": {
+                "+merge": [
+                    { "name": "work", "port": 2222 },
+                    { "name": "home", "port": null },
+                    { "name": "cloud", "host": "cloud.io" },
+                ],
+                "+key": "name",
+            },
+            "by_position": { "+merge": [{ "a": "aa" }, {}, { "c": "c" }] },
+        });
+
+        merge_values(&mut a, b);
+
+        assert_eq!(
+            a,
+            json!({
+                "by_key": [
+                    { "name": "home", "host": "home.lan" },
+                    { "name": "work", "host": "work.com", "port": 2222 },
+                    { "name": "cloud", "host": "cloud.io" },
+                ],
+                "by_position": [{ "a": "aa" }, { "b": "b" }, { "c": "c" }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_merge_values_nested_array_directives() {
+        let mut a = json!({ "name": "Patina", "editor": "vim" });
+        let b = json!({
+            "tools": { "paths": { "+append": ["~/.local/bin"] } },
+            "editor": { "plugins": { "+union": [{ "name": { "+append": "fzf" } }] } },
+        });
+
+        merge_values(&mut a, b);
+
+        assert_eq!(
+            a,
+            json!({
+                "name": "Patina",
+                "tools": { "paths": ["~/.local/bin"] },
+                "editor": { "plugins": [{ "name": ["fzf"] }] },
+            })
+        );
+    }
+
+    #[test]
+    fn test_check_array_directives() {
+        let valid = json!({
+            "a": { "+append": ["a"] },
+            "b": { "+merge": [{ "name": "b", "c": { "+prepend": 1 } }], "+key": "name" },
+            "c": [{ "d": { "+replace": [] } }],
+        });
+        assert!(check_array_directives(&valid, "vars.toml", "").is_ok());
+
+        for (value, location, message) in [
+            (
+                json!({ "paths": { "+apend": ["a"] } }),
+                "vars.toml: paths",
+                "unknown array directive `+apend`",
+            ),
+            (
+                json!({ "a": [{ "b": { "+merge": [], "+key": 1 } }] }),
+                "vars.toml: a[0].b",
+                "`+key` must be a string",
+            ),
+            (
+                json!({ "paths": { "+append": ["a"], "+prepend": ["b"] } }),
+                "vars.toml: paths",
+                "`+append` and `+prepend` can't be used together",
+            ),
+            (
+                json!({ "paths": { "+append": ["a"], "+key": "name" } }),
+                "vars.toml: paths",
+                "`+key` can only be used with `+merge`",
+            ),
+            (
+                json!({ "paths": { "+append": ["a"], "other": 1 } }),
+                "vars.toml: paths",
+                "`other` can't be set next to an array directive",
+            ),
+            (
+                json!({ "+append": ["a"], "other": 1 }),
+                "vars.toml",
+                "`other` can't be set next to an array directive",
+            ),
+        ] {
+            let result = check_array_directives(&value, "vars.toml", "");
+
+            assert!(result.is_err());
+            assert_eq!(
+                result.unwrap_err().as_invalid_array_directive(),
+                Some((&String::from(location), &String::from(message)))
+            );
+        }
+    }
+
+    #[test]
+    fn test_patina_from_file_array_directives() {
+        let tmp_dir = TmpTestDir::new();
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "array-directives-patina"
+
+                [vars]
+                extra_paths = { "+append" = ["~/.local/bin"] }
+            "#,
+        );
+
+        let patina = Patina::from_file(&path);
+
+        assert!(patina.is_ok());
+        assert_eq!(
+            patina.unwrap().vars,
+            Some(json!({ "extra_paths": ["~/.local/bin"] }))
+        );
+    }
+
+    #[test]
+    fn test_patina_from_file_invalid_array_directive() {
+        let tmp_dir = TmpTestDir::new();
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "array-directives-patina"
+
+                [[files]]
+                template = "a.txt"
+                target = "a.txt"
+                vars.extra_paths = { "+apend" = ["~/.local/bin"] }
+            "#,
+        );
+
+        let patina = Patina::from_file(&path);
+
+        assert!(patina.is_err());
+        let location = format!("{}: files[0].vars.extra_paths", path.display());
+        assert_eq!(
+            patina.unwrap_err().as_invalid_array_directive(),
+            Some((&location, &String::from("unknown array directive `+apend`")))
+        );
+    }
+
+    #[test]
+    fn test_load_vars_files_invalid_array_directive() {
+        let tmp_dir = TmpTestDir::new();
+        let vars_path = tmp_dir.write_file(
+            "vars.toml",
+            r#"
+                extra_paths = { "+append" = ["a"], "+prepend" = ["b"] }
+            "#,
+        );
+        let mut patina = Patina::default();
+
+        let load_vars = patina.load_vars_files(vec![vars_path], None);
+
+        assert!(load_vars.is_err());
+        assert!(load_vars.unwrap_err().is_invalid_array_directive());
+        assert_eq!(patina.vars, None);
+    }
+
+    #[test]
+    fn test_load_vars_files_array_directives() {
+        let tmp_dir = TmpTestDir::new();
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "array-directives-patina"
+
+                [vars]
+                extra_paths = ["~/.local/bin"]
+            "#,
+        );
+        let vars_path = tmp_dir.write_file(
+            "vars.toml",
+            r#"
+                extra_paths = { "+append" = ["/opt/homebrew/bin"] }
+            "#,
+        );
+
+        let mut patina = Patina::from_file(&path).unwrap();
+        let load_vars = patina.load_vars_files(vec![vars_path], None);
+
+        assert!(load_vars.is_ok());
+        assert_eq!(
+            patina.vars,
+            Some(json!({ "extra_paths": ["~/.local/bin", "/opt/homebrew/bin"] }))
+        );
+    }
+
+    #[test]
+    fn test_get_vars_dirs_files() {
+        let tmp_dir = TmpTestDir::new();
+        fs::create_dir_all(tmp_dir.get_file_path("vars")).unwrap();
+        fs::create_dir_all(tmp_dir.get_file_path("more-vars")).unwrap();
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "vars-dirs-patina"
+                vars_dirs = ["vars", "more-vars", "missing-vars"]
+            "#,
+        );
+        let hostname = get_facts()["hostname"].as_str().unwrap();
+        let os = get_facts()["os"].as_str().unwrap();
+        for file in [
+            String::from("vars/local.toml"),
+            format!("vars/{}.toml", hostname),
+            format!("vars/{}.toml", os),
+            String::from("vars/default.toml"),
+            String::from("vars/other-host.toml"),
+            String::from("more-vars/default.toml"),
+            format!("more-vars/{}.toml", os),
+        ] {
+            tmp_dir.write_file(&file, "");
+        }
+
+        let patina = Patina::from_file(&path).unwrap();
+
+        assert_eq!(
+            patina.get_vars_dirs_files(),
+            vec![
+                tmp_dir.get_file_path("vars/default.toml"),
+                tmp_dir.get_file_path("more-vars/default.toml"),
+                tmp_dir.get_file_path(&format!("vars/{}.toml", os)),
+                tmp_dir.get_file_path(&format!("more-vars/{}.toml", os)),
+                tmp_dir.get_file_path(&format!("vars/{}.toml", hostname)),
+                tmp_dir.get_file_path("vars/local.toml"),
+            ]
+        );
+    }
+
     #[test]
     fn test_load_vars_files() {
         let tmp_dir = TmpTestDir::new();
@@ -142,11 +779,11 @@ mod tests {
             "#,
         );
 
-        let patina = Patina::from_toml_file(&path);
+        let patina = Patina::from_file(&path);
         assert!(patina.is_ok());
         let mut patina = patina.unwrap();
 
-        let load_vars = patina.load_vars_files(vec![vars_a_path, vars_b_path]);
+        let load_vars = patina.load_vars_files(vec![vars_a_path, vars_b_path], None);
         assert!(load_vars.is_ok());
 
         assert_eq!(
@@ -163,6 +800,153 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_set_var() {
+        let cases = [
+            ("me.email=x@y.com", json!({ "me": { "email": "x@y.com" } })),
+            ("count=3", json!({ "count": 3 })),
+            ("ratio = 0.5", json!({ "ratio": 0.5 })),
+            ("enabled=true", json!({ "enabled": true })),
+            ("list=[1, \"two\"]", json!({ "list": [1, "two"] })),
+            ("quoted=\"true\"", json!({ "quoted": "true" })),
+            ("words=hello world", json!({ "words": "hello world" })),
+            ("empty=", json!({ "empty": "" })),
+            ("date=1979-05-27", json!({ "date": "1979-05-27" })),
+            ("a.b.c=null", json!({ "a": { "b": { "c": null } } })),
+        ];
+
+        for (set_var, expected) in cases {
+            let value = parse_set_var(set_var);
+            assert!(value.is_ok(), "{}", set_var);
+            assert_eq!(value.unwrap(), expected, "{}", set_var);
+        }
+    }
+
+    #[test]
+    fn test_parse_set_var_invalid() {
+        let cases = [
+            ("me.email", "expected `key=value`"),
+            ("=value", "the key path has an empty key"),
+            ("me..email=value", "the key path has an empty key"),
+        ];
+
+        for (set_var, message) in cases {
+            let value = parse_set_var(set_var);
+            assert!(value.is_err(), "{}", set_var);
+            assert_eq!(
+                value.unwrap_err().as_invalid_set_var(),
+                Some((&set_var.to_string(), &message.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_load_set_vars() {
+        let mut patina = Patina {
+            vars: Some(json!({ "me": { "name": "Patina", "email": "me@home.com" } })),
+            ..Default::default()
+        };
+
+        let load_vars = patina.load_set_vars(&[
+            String::from("me.email=me@work.com"),
+            String::from("me.name=null"),
+            String::from("editor=vim"),
+        ]);
+
+        assert!(load_vars.is_ok());
+        assert_eq!(
+            patina.vars,
+            Some(json!({ "me": { "email": "me@work.com" }, "editor": "vim" }))
+        );
+    }
+
+    #[test]
+    fn test_load_vars_files_encrypted() {
+        let tmp_dir = TmpTestDir::new();
+        let secret_key = SecretKey::Identity(write_identity_file(&tmp_dir));
+        let vars_age_path = tmp_dir.get_file_path("secrets.yaml.age");
+        let ciphertext = secrets::encrypt(&vars_age_path, "token: abc\n", &secret_key).unwrap();
+        std::fs::write(&vars_age_path, ciphertext).unwrap();
+        let vars_toml_path = tmp_dir.get_file_path("secrets.enc.toml");
+        let ciphertext = secrets::encrypt(&vars_toml_path, "key = 1\n", &secret_key).unwrap();
+        std::fs::write(&vars_toml_path, ciphertext).unwrap();
+
+        let mut patina = Patina::default();
+        let load_vars =
+            patina.load_vars_files(vec![vars_age_path, vars_toml_path], Some(&secret_key));
+
+        assert!(load_vars.is_ok());
+        assert_eq!(patina.vars, Some(json!({ "token": "abc", "key": 1 })));
+    }
+
+    #[test]
+    fn test_load_vars_files_encrypted_without_key() {
+        let tmp_dir = TmpTestDir::new();
+        let vars_path = tmp_dir.write_file("secrets.enc.toml", "");
+
+        let mut patina = Patina::default();
+        let load_vars = patina.load_vars_files(vec![vars_path.clone()], None);
+
+        assert!(load_vars.is_err());
+        assert_eq!(
+            load_vars.unwrap_err().as_missing_secret_key(),
+            Some(&vars_path)
+        );
+    }
+
+    #[test]
+    fn test_load_vars_files_json_and_yaml() {
+        let tmp_dir = TmpTestDir::new();
+        let vars_json_path = tmp_dir.write_file(
+            "vars.json",
+            r#"{ "me": { "email": "json@mail.com" }, "json_var": true }"#,
+        );
+        let vars_yaml_path = tmp_dir.write_file(
+            "vars.yml",
+            r#"
+me:
+  email: yaml@mail.com
+yaml_var: [1, 2]
+"#,
+        );
+
+        let mut patina = Patina {
+            vars: Some(json!({ "me": { "name": "Patina" } })),
+            ..Default::default()
+        };
+
+        let load_vars = patina.load_vars_files(vec![vars_json_path, vars_yaml_path], None);
+        assert!(load_vars.is_ok());
+
+        assert_eq!(
+            patina.vars,
+            Some(json!({
+                "me": {
+                    "name": "Patina",
+                    "email": "yaml@mail.com"
+                },
+                "json_var": true,
+                "yaml_var": [1, 2]
+            }))
+        );
+    }
+
+    #[test]
+    fn test_load_vars_files_yaml_null_removes_var() {
+        let tmp_dir = TmpTestDir::new();
+        let vars_yaml_path = tmp_dir.write_file("vars.yaml", "me:\n  email: null\n");
+
+        let mut patina = Patina {
+            vars: Some(json!({ "me": { "name": "Patina", "email": "patina@mail.com" } })),
+            ..Default::default()
+        };
+
+        let load_vars = patina.load_vars_files(vec![vars_yaml_path], None);
+        assert!(load_vars.is_ok());
+
+        assert_eq!(patina.vars, Some(json!({ "me": { "name": "Patina" } })));
+    }
+
     #[test]
     fn test_load_vars_files_file_does_not_exist() {
         let tmp_dir = TmpTestDir::new();
@@ -181,12 +965,12 @@ mod tests {
             "#,
         );
 
-        let patina = Patina::from_toml_file(&path);
+        let patina = Patina::from_file(&path);
         assert!(patina.is_ok());
         let mut patina = patina.unwrap();
 
         let load_vars =
-            patina.load_vars_files(vec![PathBuf::from("this/path/does/not/exist.toml")]);
+            patina.load_vars_files(vec![PathBuf::from("this/path/does/not/exist.toml")], None);
         assert!(load_vars.is_err());
         let err = load_vars.unwrap_err();
         assert!(err.is_file_read())
@@ -216,11 +1000,11 @@ mod tests {
             "#,
         );
 
-        let patina = Patina::from_toml_file(&path);
+        let patina = Patina::from_file(&path);
         assert!(patina.is_ok());
         let mut patina = patina.unwrap();
 
-        let load_vars = patina.load_vars_files(vec![invalid_vars_path]);
+        let load_vars = patina.load_vars_files(vec![invalid_vars_path], None);
         assert!(load_vars.is_err());
         let err = load_vars.unwrap_err();
         assert!(err.is_toml_parse())
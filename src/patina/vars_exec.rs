@@ -0,0 +1,345 @@
+//! [Patina] variables that are sourced from the output of shell commands.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Mutex, OnceLock};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::patina::Patina;
+use crate::utils::{shell_command, Error, Result};
+
+/// A [VarsExec] entry defines variables from the trimmed stdout of shell commands
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum VarsExec {
+    /// A command whose output is the value of the variable
+    Command(String),
+
+    /// A command with options
+    Options(VarsExecOptions),
+
+    /// A table of nested variables
+    Table(BTreeMap<String, VarsExec>),
+}
+
+/// A command for a [VarsExec] variable, with options
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VarsExecOptions {
+    /// The command whose output is the value of the variable
+    pub command: String,
+
+    /// Whether to run the command at most once per dotpatina run, reusing its output after that.
+    /// This avoids running slow commands again when more than one variable uses them.
+    #[serde(default)]
+    pub cache: bool,
+
+    /// The directory to run the command from, if it differs from the [Patina]'s.
+    /// This is set for commands that come from included patinas.
+    #[serde(skip)]
+    pub base_path: Option<PathBuf>,
+}
+
+impl VarsExec {
+    /// Set the directory that this entry's commands run from, for commands that don't have one yet
+    pub(super) fn set_base_path(&mut self, base_path: &Option<PathBuf>) {
+        match self {
+            VarsExec::Command(command) => {
+                *self = VarsExec::Options(VarsExecOptions {
+                    command: std::mem::take(command),
+                    cache: false,
+                    base_path: base_path.clone(),
+                })
+            }
+            VarsExec::Options(options) => {
+                if options.base_path.is_none() {
+                    options.base_path = base_path.clone();
+                }
+            }
+            VarsExec::Table(table) => table.values_mut().for_each(|e| e.set_base_path(base_path)),
+        }
+    }
+}
+
+/// Overlay a table of [VarsExec] entries onto another, merging nested tables
+pub(super) fn merge_vars_exec(
+    target: &mut BTreeMap<String, VarsExec>,
+    source: BTreeMap<String, VarsExec>,
+) {
+    for (name, entry) in source {
+        match (target.get_mut(&name), entry) {
+            (Some(VarsExec::Table(target)), VarsExec::Table(source)) => {
+                merge_vars_exec(target, source)
+            }
+            (_, entry) => {
+                target.insert(name, entry);
+            }
+        }
+    }
+}
+
+impl Patina {
+    /// Run the `vars_exec` commands and overlay their output onto the vars.
+    /// Commands run from the directory of the patina that defined them.
+    pub fn load_vars_exec(&mut self) -> Result<()> {
+        if self.vars_exec.is_empty() {
+            return Ok(());
+        }
+
+        let dir = self.get_base_path();
+        let vars = run_vars_exec_table(&self.vars_exec, &dir)?;
+        self.overlay_vars(vars);
+        Ok(())
+    }
+}
+
+/// Run all the commands in a table of [VarsExec] entries, returning their outputs in the same shape
+fn run_vars_exec_table(table: &BTreeMap<String, VarsExec>, dir: &Path) -> Result<Value> {
+    let mut vars = Map::new();
+    for (name, entry) in table {
+        let value = match entry {
+            VarsExec::Command(command) => Value::String(run_command(command, dir, false)?),
+            VarsExec::Options(options) => {
+                let dir = options.base_path.as_deref().unwrap_or(dir);
+                Value::String(run_command(&options.command, dir, options.cache)?)
+            }
+            VarsExec::Table(table) => run_vars_exec_table(table, dir)?,
+        };
+        vars.insert(name.clone(), value);
+    }
+    Ok(Value::Object(vars))
+}
+
+/// Run a command with the system shell from the given directory, returning its trimmed stdout.
+/// If `cache` is set, the output of an earlier run of the same command from the same directory is reused.
+fn run_command(command: &str, dir: &Path, cache: bool) -> Result<String> {
+    static CACHE: OnceLock<Mutex<HashMap<(String, PathBuf), String>>> = OnceLock::new();
+    let cache_key = (command.to_string(), dir.to_path_buf());
+    if cache {
+        let cached = CACHE.get_or_init(Default::default).lock().unwrap();
+        if let Some(output) = cached.get(&cache_key) {
+            info!("using cached vars_exec output: {}", command);
+            return Ok(output.clone());
+        }
+    }
+
+    info!(
+        "running vars_exec command in {}: {}",
+        dir.display(),
+        command
+    );
+    let output = shell_command(command)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .output();
+    let output = match output {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        }
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            return Err(Error::VarsExecFailed(command.to_string(), stderr));
+        }
+        Err(e) => return Err(Error::VarsExecFailed(command.to_string(), e.to_string())),
+    };
+
+    if cache {
+        let mut cached = CACHE.get_or_init(Default::default).lock().unwrap();
+        cached.insert(cache_key, output.clone());
+    }
+    Ok(output)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::tests::test_utils::TmpTestDir;
+
+    #[test]
+    fn test_patina_deserialize_vars_exec() {
+        let patina = r#"
+            name = "vars-exec-patina"
+
+            [vars_exec]
+            gpg_key = "gpg --list-secret-keys"
+            brew.prefix = { command = "brew --prefix", cache = true }
+        "#;
+
+        let patina = toml::from_str::<Patina>(patina);
+
+        assert!(patina.is_ok());
+        let patina = patina.unwrap();
+        assert_eq!(
+            patina.vars_exec.get("gpg_key"),
+            Some(&VarsExec::Command(String::from("gpg --list-secret-keys")))
+        );
+        assert_eq!(
+            patina.vars_exec.get("brew"),
+            Some(&VarsExec::Table(BTreeMap::from([(
+                String::from("prefix"),
+                VarsExec::Options(VarsExecOptions {
+                    command: String::from("brew --prefix"),
+                    cache: true,
+                    base_path: None,
+                })
+            )])))
+        );
+    }
+
+    #[test]
+    fn test_patina_load_vars_exec() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file("name.txt", "Patina\n");
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "vars-exec-patina"
+
+                [vars]
+                me.name = "overwritten"
+                me.email = "me@home.com"
+
+                [vars_exec]
+                me.name = "cat name.txt"
+                greeting = "echo '  hello  '"
+            "#,
+        );
+
+        let mut patina = Patina::from_file(&path).unwrap();
+        let load_vars_exec = patina.load_vars_exec();
+
+        assert!(load_vars_exec.is_ok());
+        assert_eq!(
+            patina.vars,
+            Some(json!({
+                "me": { "name": "Patina", "email": "me@home.com" },
+                "greeting": "hello",
+            }))
+        );
+    }
+
+    #[test]
+    fn test_patina_load_vars_exec_failure() {
+        let tmp_dir = TmpTestDir::new();
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "vars-exec-patina"
+
+                [vars_exec]
+                broken = "echo oops >&2; exit 1"
+            "#,
+        );
+
+        let mut patina = Patina::from_file(&path).unwrap();
+        let load_vars_exec = patina.load_vars_exec();
+
+        assert!(load_vars_exec.is_err());
+        assert_eq!(
+            load_vars_exec.unwrap_err().as_vars_exec_failed(),
+            Some((
+                &String::from("echo oops >&2; exit 1"),
+                &String::from("oops")
+            ))
+        );
+    }
+
+    #[test]
+    fn test_patina_load_vars_exec_cache() {
+        let tmp_dir = TmpTestDir::new();
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "vars-exec-patina"
+
+                [vars_exec]
+                cached = { command = "echo run >> cached.log; wc -l < cached.log", cache = true }
+                uncached = "echo run >> uncached.log; wc -l < uncached.log"
+            "#,
+        );
+
+        let mut first = Patina::from_file(&path).unwrap();
+        first.load_vars_exec().unwrap();
+        let mut second = Patina::from_file(&path).unwrap();
+        second.load_vars_exec().unwrap();
+
+        assert_eq!(first.vars, Some(json!({ "cached": "1", "uncached": "1" })));
+        assert_eq!(second.vars, Some(json!({ "cached": "1", "uncached": "2" })));
+        assert_eq!(
+            fs::read_to_string(tmp_dir.get_file_path("cached.log")).unwrap(),
+            "run\n"
+        );
+    }
+
+    #[test]
+    fn test_patina_from_file_does_not_run_vars_exec() {
+        let tmp_dir = TmpTestDir::new();
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "vars-exec-patina"
+
+                [vars_exec]
+                ran = "touch ran.txt"
+            "#,
+        );
+
+        let patina = Patina::from_file(&path);
+
+        assert!(patina.is_ok());
+        assert_eq!(patina.unwrap().vars, None);
+        assert!(!tmp_dir.get_file_path("ran.txt").exists());
+    }
+
+    #[test]
+    fn test_patina_load_vars_exec_included() {
+        let tmp_dir = TmpTestDir::new();
+        fs::create_dir_all(tmp_dir.get_file_path("base")).unwrap();
+        tmp_dir.write_file("base/name.txt", "Base");
+        tmp_dir.write_file("name.txt", "Overlay");
+        tmp_dir.write_file(
+            "base/patina.toml",
+            r#"
+                name = "base"
+
+                [vars_exec]
+                base.name = "cat name.txt"
+                me.name = "cat name.txt"
+                me.email = "echo base@mail.com"
+            "#,
+        );
+        let path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "overlay"
+                include = ["base/patina.toml"]
+
+                [vars]
+                me.name = "overwritten"
+
+                [vars_exec]
+                me.name = "cat name.txt"
+            "#,
+        );
+
+        let mut patina = Patina::from_file(&path).unwrap();
+        let load_vars_exec = patina.load_vars_exec();
+
+        assert!(load_vars_exec.is_ok());
+        assert_eq!(
+            patina.vars,
+            Some(json!({
+                "base": { "name": "Base" },
+                "me": { "name": "Overlay", "email": "base@mail.com" },
+            }))
+        );
+    }
+}
@@ -0,0 +1,277 @@
+//! Versioning of the [super::Patina] file schema, and migrating older patina files to the current version.
+//!
+//! - Version 1 is the original schema, where every template is rendered with handlebars.
+//!   Patina files without a `version` are version 1.
+//! - Version 2 only renders templates with a `.hbs` extension, unless a file sets `render`.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use toml_edit::DocumentMut;
+
+use super::format;
+use super::patina_file::TEMPLATE_EXTENSION;
+use crate::utils::{Error, Result};
+
+/// The latest version of the patina file schema
+pub const CURRENT_VERSION: u32 = 2;
+
+/// The version of a patina file schema
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct PatinaVersion(pub u32);
+
+impl PatinaVersion {
+    /// The version of patina files that don't set one
+    pub fn unversioned() -> PatinaVersion {
+        PatinaVersion(1)
+    }
+}
+
+impl Default for PatinaVersion {
+    fn default() -> Self {
+        PatinaVersion(CURRENT_VERSION)
+    }
+}
+
+/// The part of a patina file that is read before the rest, to decide how to read it
+#[derive(Deserialize)]
+struct VersionHeader {
+    /// The schema version of the file
+    #[serde(default = "PatinaVersion::unversioned")]
+    version: PatinaVersion,
+}
+
+/// Read the schema version of patina data, in the format given by the extension of the path it was read from.
+/// Returns an error if the version is not one that this version of dotpatina can read.
+pub fn read_version(path: &Path, data: &str) -> Result<u32> {
+    value_version(path, &format::parse_value(path, data)?)
+}
+
+/// Get the schema version of parsed patina data.
+/// Returns an error if the version is not one that this version of dotpatina can read.
+pub fn value_version(path: &Path, value: &Value) -> Result<u32> {
+    let header = match VersionHeader::deserialize(value) {
+        Ok(header) => header,
+        Err(e) => return Err(Error::PatinaData(path.to_path_buf(), e)),
+    };
+    match header.version.0 {
+        version @ 1..=CURRENT_VERSION => Ok(version),
+        version => Err(Error::UnsupportedVersion(path.to_path_buf(), version)),
+    }
+}
+
+/// Migrate parsed patina data from the given version to the current version
+pub fn migrate_value(value: &mut Value, version: u32) {
+    let Some(patina) = value.as_object_mut() else {
+        return;
+    };
+
+    if version < 2 {
+        let files = patina.get_mut("files").and_then(Value::as_array_mut);
+        for file in files.into_iter().flatten().filter_map(Value::as_object_mut) {
+            let template = file.get("template").and_then(Value::as_str);
+            let link = file.get("link").and_then(Value::as_bool);
+            if needs_explicit_render(template, link, file.contains_key("render")) {
+                file.insert(String::from("render"), Value::Bool(true));
+            }
+        }
+    }
+
+    patina.insert(String::from("version"), Value::from(CURRENT_VERSION));
+}
+
+/// Migrate the contents of a TOML patina file from the given version to the current version,
+/// keeping its comments and formatting.
+pub fn migrate_toml(path: &Path, data: &str, version: u32) -> Result<String> {
+    let mut doc = match data.parse::<DocumentMut>() {
+        Ok(doc) => doc,
+        Err(e) => return Err(Error::Message(format!("{}: {}", path.display(), e))),
+    };
+
+    if version < 2 {
+        if let Some(files) = doc.get_mut("files") {
+            migrate_toml_files_render(files);
+        }
+    }
+
+    // The version goes first, taking over any comments at the top of the file
+    let root = doc.as_table_mut();
+    let first_key = root.iter().next().map(|(key, _)| key.to_string());
+    let first_key_decor = first_key
+        .filter(|key| key != "version")
+        .and_then(|key| root.key_mut(&key))
+        .map(|mut key| std::mem::take(key.leaf_decor_mut()));
+    root.insert("version", toml_edit::value(i64::from(CURRENT_VERSION)));
+    root.sort_values_by(|a, _, b, _| (b.get() == "version").cmp(&(a.get() == "version")));
+    if let (Some(decor), Some(mut version_key)) = (first_key_decor, root.key_mut("version")) {
+        *version_key.leaf_decor_mut() = decor;
+    }
+
+    Ok(doc.to_string())
+}
+
+/// Set `render = true` on the files of a version 1 TOML patina that would no longer be rendered in version 2.
+/// Files can be written as an array of tables or as an inline array.
+fn migrate_toml_files_render(files: &mut toml_edit::Item) {
+    if let Some(files) = files.as_array_of_tables_mut() {
+        for file in files.iter_mut() {
+            let template = file.get("template").and_then(|t| t.as_str());
+            let link = file.get("link").and_then(|l| l.as_bool());
+            if needs_explicit_render(template, link, file.contains_key("render")) {
+                file.insert("render", toml_edit::value(true));
+            }
+        }
+    } else if let Some(files) = files.as_array_mut() {
+        for file in files.iter_mut().filter_map(|f| f.as_inline_table_mut()) {
+            let template = file.get("template").and_then(|t| t.as_str());
+            let link = file.get("link").and_then(|l| l.as_bool());
+            if needs_explicit_render(template, link, file.contains_key("render")) {
+                file.insert("render", true.into());
+                file.fmt();
+            }
+        }
+    }
+}
+
+/// Whether a version 1 file needs `render = true` to keep being rendered in version 2.
+/// Linked files and files that already set `render` are left alone.
+fn needs_explicit_render(template: Option<&str>, link: Option<bool>, has_render: bool) -> bool {
+    let is_template = template
+        .and_then(|t| Path::new(t).extension())
+        .is_some_and(|e| e == TEMPLATE_EXTENSION);
+    !has_render && link != Some(true) && !is_template
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_read_version() {
+        let path = Path::new("patina.toml");
+
+        assert_eq!(read_version(path, "name = \"a\"").unwrap(), 1);
+        assert_eq!(read_version(path, "version = 1").unwrap(), 1);
+        assert_eq!(read_version(path, "version = 2").unwrap(), 2);
+    }
+
+    #[test]
+    fn test_read_version_unsupported() {
+        let path = Path::new("patina.toml");
+
+        for version in [0, CURRENT_VERSION + 1] {
+            let result = read_version(path, &format!("version = {}", version));
+
+            assert!(result.is_err());
+            assert_eq!(
+                result.unwrap_err().as_unsupported_version(),
+                Some((&path.to_path_buf(), &version))
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_version_invalid() {
+        let path = Path::new("patina.toml");
+
+        let result = read_version(path, "version = \"2\"");
+
+        assert!(result.is_err());
+        assert_eq!(result.unwrap_err().as_patina_data().unwrap().0, path);
+    }
+
+    #[test]
+    fn test_migrate_value() {
+        let mut value = json!({
+            "name": "old-patina",
+            "files": [
+                { "template": "a.txt", "target": "a.txt" },
+                { "template": "b.txt.hbs", "target": "b.txt" },
+                { "template": "c.txt", "target": "c.txt", "render": false },
+                { "template": "d.txt", "target": "d.txt", "link": true },
+            ],
+        });
+
+        migrate_value(&mut value, 1);
+
+        assert_eq!(
+            value,
+            json!({
+                "name": "old-patina",
+                "version": 2,
+                "files": [
+                    { "template": "a.txt", "target": "a.txt", "render": true },
+                    { "template": "b.txt.hbs", "target": "b.txt" },
+                    { "template": "c.txt", "target": "c.txt", "render": false },
+                    { "template": "d.txt", "target": "d.txt", "link": true },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn test_migrate_toml() {
+        let data = r#"# My dotfiles
+name = "old-patina" # the name
+
+[vars]
+editor = "vim"
+
+# Shell config
+[[files]]
+template = "zshrc"
+target = "~/.zshrc"
+
+[[files]]
+template = "gitconfig.hbs"
+target = "~/.gitconfig"
+"#;
+
+        let result = migrate_toml(Path::new("patina.toml"), data, 1);
+
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            r#"# My dotfiles
+version = 2
+name = "old-patina" # the name
+
+[vars]
+editor = "vim"
+
+# Shell config
+[[files]]
+template = "zshrc"
+target = "~/.zshrc"
+render = true
+
+[[files]]
+template = "gitconfig.hbs"
+target = "~/.gitconfig"
+"#
+        );
+    }
+
+    #[test]
+    fn test_migrate_toml_inline_files() {
+        let data = r#"version = 1
+name = "old-patina"
+files = [{ template = "zshrc", target = "~/.zshrc" }]
+"#;
+
+        let result = migrate_toml(Path::new("patina.toml"), data, 1);
+
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            r#"version = 2
+name = "old-patina"
+files = [{ template = "zshrc", target = "~/.zshrc", render = true }]
+"#
+        );
+    }
+}
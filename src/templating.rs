@@ -1,21 +1,28 @@
 //! Structures and functions for processing Patina templates.
 //! Templating uses the [Handlebars](https://handlebarsjs.com/guide/) templating language.
 
+use std::borrow::Cow;
 use std::fs;
+use std::path::{Component, Path, PathBuf};
 
 use handlebars::Handlebars;
-use log::info;
+use log::{info, warn};
+use serde_json::Value;
 
-use crate::patina::patina_file::PatinaFile;
+pub mod facts;
+
+use crate::patina::patina_file::{PatinaFile, TEMPLATE_EXTENSION};
+use crate::patina::tags::TagExpression;
 use crate::patina::Patina;
-use crate::utils::{Error, Result};
+use crate::utils::{is_binary, list_files_in_dir, Error, Result};
 
 /// [PatinaFileRender] is an object that holds a reference to a [PatinaFile] and a
 /// [String] of the final render.
 #[derive(Debug)]
 pub struct PatinaFileRender<'pf> {
-    /// A reference to the [PatinaFile]
-    pub patina_file: &'pf PatinaFile,
+    /// The rendered [PatinaFile].
+    /// This is owned when it was expanded from another [PatinaFile], like a template directory.
+    pub patina_file: Cow<'pf, PatinaFile>,
 
     /// Whether or not the file has changes.
     /// - [None]: if the file has not been diffed with the target yet
@@ -25,47 +32,308 @@ pub struct PatinaFileRender<'pf> {
 
     /// The full render string for this file
     pub render_str: String,
+
+    /// The raw contents of a binary file, which is copied without templating.
+    /// When this is set, `render_str` is empty.
+    pub render_bytes: Option<Vec<u8>>,
+
+    /// The full contents of the target file, when only part of it is rendered, like for a managed block
+    pub target_str: Option<String>,
 }
 
-/// Renders all the [PatinaFile]s in a [Patina].
-pub fn render_patina(patina: &Patina, tags: Option<Vec<String>>) -> Result<Vec<PatinaFileRender>> {
+impl PatinaFileRender<'_> {
+    /// The contents to write to the target file
+    pub fn contents(&self) -> &[u8] {
+        match (&self.target_str, &self.render_bytes) {
+            (Some(target_str), _) => target_str.as_bytes(),
+            (None, Some(bytes)) => bytes,
+            (None, None) => self.render_str.as_bytes(),
+        }
+    }
+}
+
+/// [PatinaRender] holds the [PatinaFileRender]s for a [Patina], along with the files that were skipped.
+#[derive(Debug)]
+pub struct PatinaRender<'pf> {
+    /// The rendered files
+    pub files: Vec<PatinaFileRender<'pf>>,
+
+    /// The files that were skipped because their `when` condition was false
+    pub skipped_files: Vec<&'pf PatinaFile>,
+}
+
+/// Create the [Handlebars] instance used for rendering Patina templates
+pub fn new_handlebars() -> Handlebars<'static> {
     let mut hb = Handlebars::new();
     hb.register_escape_fn(handlebars::no_escape);
     hb.set_strict_mode(true);
+    hb
+}
+
+/// Get the context for rendering a [PatinaFile], which is its vars along with the system facts as `facts`.
+/// Facts replace any vars named `facts`, so that they are the same in every template. Validation reports those vars.
+pub fn render_context(patina: &Patina, patina_file: &PatinaFile) -> Value {
+    let mut context = match &*patina.get_file_vars(patina_file) {
+        Some(Value::Object(vars)) => vars.clone(),
+        _ => Default::default(),
+    };
+    context.insert(String::from("facts"), facts::get_facts().clone());
+    Value::Object(context)
+}
+
+/// Renders all the [PatinaFile]s in a [Patina].
+pub fn render_patina(patina: &Patina, tags: Option<TagExpression>) -> Result<PatinaRender<'_>> {
+    let hb = new_handlebars();
+
+    let mut renders = vec![];
+    let mut skipped_files = vec![];
+    for pf in patina.files_for_tags(tags) {
+        let context = render_context(patina, pf);
+        if !evaluate_when(&hb, &context, pf)? {
+            info!("skipping patina file: {}", pf.template.display());
+            skipped_files.push(pf);
+            continue;
+        }
+
+        for pf in expand_patina_file(&hb, patina, pf, &context)? {
+            renders.push(render_patina_file(&hb, patina, &context, pf)?);
+        }
+    }
+
+    Ok(PatinaRender {
+        files: renders,
+        skipped_files,
+    })
+}
+
+/// Evaluate the `when` condition of a [PatinaFile] to determine whether it should be rendered.
+///
+/// A condition containing `{{` is rendered as a template, and is false if it renders to an empty string, `false`, or `0`.
+/// Otherwise, the condition is evaluated as a handlebars expression, like `work` or `eq facts.os "linux"`.
+pub fn evaluate_when(hb: &Handlebars, context: &Value, patina_file: &PatinaFile) -> Result<bool> {
+    let Some(when) = &patina_file.when else {
+        return Ok(true);
+    };
+
+    let template = if when.contains("{{") {
+        when.clone()
+    } else if when.trim().contains(char::is_whitespace) {
+        format!("{{{{#if ({})}}}}true{{{{/if}}}}", when.trim())
+    } else {
+        format!("{{{{#if {}}}}}true{{{{/if}}}}", when.trim())
+    };
 
-    patina
-        .files_for_tags(tags)
-        .map(|pf| {
-            let render = render_patina_file(&hb, patina, pf)?;
-            Ok(PatinaFileRender {
-                patina_file: pf,
-                render_str: render,
-                any_changes: None,
-            })
-        })
-        .collect()
+    match hb.render_template(&template, context) {
+        Ok(render) => Ok(!matches!(render.trim(), "" | "false" | "0")),
+        Err(mut e) => {
+            e.template_name = Some(format!("{} (when)", patina_file.template.display()));
+            Err(Error::RenderTemplate(e))
+        }
+    }
 }
 
-/// Render a single [PatinaFile] to a string.
-fn render_patina_file(
+/// Expand a [PatinaFile] into the individual files that it renders, using its [render_context].
+/// - Template and target paths containing `{{` are rendered with the file's vars first.
+/// - A glob template expands to one file for each match, targeting the match's file name in the target directory.
+/// - A template directory expands to one file for each file under it, targeting the same relative path under the target.
+pub fn expand_patina_file<'pf>(
     hb: &Handlebars,
+    patina: &Patina,
+    patina_file: &'pf PatinaFile,
+    context: &Value,
+) -> Result<Vec<Cow<'pf, PatinaFile>>> {
+    let patina_file = render_patina_file_paths(hb, patina_file, context)?;
+    let patina_files = match patina_file.is_template_glob() {
+        true => {
+            let matches = expand_patina_file_glob(patina, &patina_file)?;
+            if matches.is_empty() {
+                warn!(
+                    "template glob matches no files: {}",
+                    patina_file.template.display()
+                );
+            }
+            matches.into_iter().map(Cow::Owned).collect()
+        }
+        false => vec![patina_file],
+    };
+
+    let mut result = vec![];
+    for pf in patina_files {
+        let template_path = patina.get_template_path(&pf);
+        if !template_path.is_dir() {
+            result.push(pf);
+            continue;
+        }
+
+        info!("expanding template directory: {}", template_path.display());
+        for relative_path in list_files_in_dir(&template_path)? {
+            result.push(Cow::Owned(with_expanded_target(PatinaFile {
+                template: pf.template.join(&relative_path),
+                target: pf.target.join(&relative_path),
+                ..(*pf).clone()
+            })));
+        }
+    }
+
+    Ok(result)
+}
+
+/// Render the template and target paths of a [PatinaFile], if either of them is templated
+fn render_patina_file_paths<'pf>(
+    hb: &Handlebars,
+    patina_file: &'pf PatinaFile,
+    context: &Value,
+) -> Result<Cow<'pf, PatinaFile>> {
+    if !patina_file.is_templated_path() {
+        return Ok(Cow::Borrowed(patina_file));
+    }
+
+    let render_path = |path: &Path| -> Result<PathBuf> {
+        if !path.to_string_lossy().contains("{{") {
+            return Ok(path.to_path_buf());
+        }
+
+        let path_str = path.to_string_lossy();
+        let render = match hb.render_template(&path_str, context) {
+            Ok(render) => render,
+            Err(mut e) => {
+                e.template_name = Some(path_str.to_string());
+                return Err(Error::RenderTemplate(e));
+            }
+        };
+        info!("rendered path: {} -> {}", path_str, render);
+
+        let rendered_path = PathBuf::from(&render);
+        let parent_dirs = |p: &Path| {
+            p.components()
+                .filter(|c| *c == Component::ParentDir)
+                .count()
+        };
+        let problem = if render.trim().is_empty() {
+            Some("renders to an empty path")
+        } else if render.contains('\0') {
+            Some("renders to a path containing a NUL character")
+        } else if rendered_path.parent().is_none() {
+            Some("renders to a root path")
+        } else if parent_dirs(&rendered_path) > parent_dirs(path) {
+            Some("renders to a path that escapes its directory with `..`")
+        } else {
+            None
+        };
+
+        match problem {
+            Some(problem) => Err(Error::UnsafePath(path.to_path_buf(), problem.to_string())),
+            None => Ok(rendered_path),
+        }
+    };
+
+    Ok(Cow::Owned(PatinaFile {
+        template: render_path(&patina_file.template)?,
+        target: render_path(&patina_file.target)?,
+        ..patina_file.clone()
+    }))
+}
+
+/// Expand a [PatinaFile] with a glob template into a [PatinaFile] for each match
+pub fn expand_patina_file_glob(
     patina: &Patina,
     patina_file: &PatinaFile,
-) -> Result<String> {
+) -> Result<Vec<PatinaFile>> {
+    let pattern = patina.get_template_path(patina_file);
+    info!("expanding template glob: {}", pattern.display());
+
+    let matches = match glob::glob(&pattern.to_string_lossy()) {
+        Ok(matches) => matches,
+        Err(e) => return Err(Error::InvalidGlob(patina_file.template.clone(), e)),
+    };
+
+    let base_path = patina.get_patina_file_base_path(patina_file);
+    let mut files = vec![];
+    for template_path in matches {
+        let template_path = match template_path {
+            Ok(template_path) => template_path,
+            Err(e) => return Err(Error::FileRead(e.path().to_path_buf(), e.into())),
+        };
+        let Some(file_name) = template_path.file_name() else {
+            continue;
+        };
+
+        files.push(with_expanded_target(PatinaFile {
+            template: template_path
+                .strip_prefix(&base_path)
+                .unwrap_or(&template_path)
+                .to_path_buf(),
+            target: patina_file.target.join(file_name),
+            ..patina_file.clone()
+        }));
+    }
+
+    Ok(files)
+}
+
+/// Strip the `.hbs` extension from the target of a rendered [PatinaFile] that was expanded from a directory or glob
+fn with_expanded_target(mut patina_file: PatinaFile) -> PatinaFile {
+    let is_template = patina_file
+        .target
+        .extension()
+        .is_some_and(|e| e == TEMPLATE_EXTENSION);
+    if is_template && patina_file.is_rendered() {
+        patina_file.target.set_extension("");
+    }
+    patina_file
+}
+
+/// Render a single [PatinaFile] with its [render_context].
+/// Binary files are never templated, and are carried as raw bytes.
+fn render_patina_file<'pf>(
+    hb: &Handlebars,
+    patina: &Patina,
+    context: &Value,
+    patina_file: Cow<'pf, PatinaFile>,
+) -> Result<PatinaFileRender<'pf>> {
     info!("rendering patina file: {}", patina_file.template.display());
 
-    let template_path = patina.get_patina_path(&patina_file.template);
-    let template_str = match fs::read_to_string(&template_path) {
-        Ok(template_str) => template_str,
+    let template_path = patina.get_template_path(&patina_file);
+    let template_bytes = match fs::read(&template_path) {
+        Ok(template_bytes) => template_bytes,
         Err(e) => return Err(Error::FileRead(template_path, e)),
     };
 
-    match hb.render_template(&template_str, &patina.vars) {
-        Ok(render) => Ok(render),
-        Err(mut e) => {
-            e.template_name = Some(patina_file.template.display().to_string());
-            Err(Error::RenderTemplate(e))
-        }
+    if is_binary(&template_bytes) {
+        return Ok(binary_render(patina_file, template_bytes));
+    }
+    let template_str = String::from_utf8_lossy(&template_bytes).into_owned();
+
+    // Raw and linked files are copied verbatim
+    let render_str = match patina_file.is_rendered() {
+        false => template_str,
+        true => match hb.render_template(&template_str, context) {
+            Ok(render) => render,
+            Err(mut e) => {
+                e.template_name = Some(patina_file.template.display().to_string());
+                return Err(Error::RenderTemplate(e));
+            }
+        },
+    };
+
+    Ok(PatinaFileRender {
+        patina_file,
+        any_changes: None,
+        render_str,
+        render_bytes: None,
+        target_str: None,
+    })
+}
+
+/// Create a [PatinaFileRender] for a binary file
+fn binary_render(patina_file: Cow<'_, PatinaFile>, bytes: Vec<u8>) -> PatinaFileRender<'_> {
+    info!("copying binary file: {}", patina_file.template.display());
+    PatinaFileRender {
+        patina_file,
+        any_changes: None,
+        render_str: String::new(),
+        render_bytes: Some(bytes),
+        target_str: None,
     }
 }
 
@@ -104,16 +372,17 @@ Templates use the Handebars templating language. For more information, see <http
                 template_path,
                 PathBuf::from("tests/fixtures/template.txt"),
             )],
+            ..Default::default()
         };
 
         let render = render_patina(&patina, None);
 
         assert!(render.is_ok());
-        let render = render.unwrap();
+        let render = render.unwrap().files;
         assert_eq!(render.len(), 1);
         let render = &render[0];
 
-        assert_eq!(render.patina_file, &patina.files[0]);
+        assert_eq!(*render.patina_file, patina.files[0]);
 
         assert_eq!(
             render.render_str,
@@ -145,12 +414,13 @@ Templates use the Handebars templating language. For more information, see <http
                 PatinaFile::new(template_b_path, PathBuf::from("output_b.txt")),
                 PatinaFile::new(template_c_path, PathBuf::from("output_c.txt")),
             ],
+            ..Default::default()
         };
 
         let render = render_patina(&patina, None);
 
         assert!(render.is_ok());
-        let render = render.unwrap();
+        let render = render.unwrap().files;
 
         assert_eq!(render.len(), 3);
         assert_eq!(render[0].render_str, "This is template_a.");
@@ -175,6 +445,7 @@ Templates use the Handebars templating language. For more information, see <http
                 template_path,
                 PathBuf::from("tests/fixtures/template.txt"),
             )],
+            ..Default::default()
         };
 
         let render = render_patina(&patina, None);
@@ -208,6 +479,7 @@ Templates use the Handebars templating language. For more information, see <http
                 invalid_template_path,
                 PathBuf::from("tests/fixtures/template.txt"),
             )],
+            ..Default::default()
         };
 
         let render = render_patina(&patina, None);
@@ -234,13 +506,426 @@ Templates use the Handebars templating language. For more information, see <http
                 template_path,
                 PathBuf::from("tests/fixtures/output.txt"),
             )],
+            ..Default::default()
         };
 
         let render = render_patina(&patina, None);
         assert!(render.is_ok());
         assert_eq!(
-            render.unwrap()[0].render_str,
+            render.unwrap().files[0].render_str,
             "This file has {{ escaped }} handlebars\n"
         );
     }
+
+    #[test]
+    fn test_render_patina_template_directory() {
+        let tmp_dir = TmpTestDir::new();
+        fs::create_dir_all(tmp_dir.get_file_path("nvim/lua")).unwrap();
+        tmp_dir.write_file("nvim/init.lua", "require('options')");
+        tmp_dir.write_file("nvim/lua/options.lua.hbs", "vim.opt.number = {{ number }}");
+
+        let patina = Patina {
+            base_path: Some(tmp_dir.path.clone()),
+            name: String::from("template-directory"),
+            description: String::from("This is a patina with a template directory"),
+            vars: Some(json!({ "number": true })),
+            files: vec![PatinaFile::new("nvim", "output/nvim")],
+            ..Default::default()
+        };
+
+        let render = render_patina(&patina, None);
+
+        assert!(render.is_ok());
+        let render = render.unwrap().files;
+        assert_eq!(render.len(), 2);
+
+        assert_eq!(
+            render[0].patina_file.template,
+            PathBuf::from("nvim/init.lua")
+        );
+        assert_eq!(
+            render[0].patina_file.target,
+            PathBuf::from("output/nvim/init.lua")
+        );
+        assert_eq!(render[0].render_str, "require('options')");
+
+        assert_eq!(
+            render[1].patina_file.template,
+            PathBuf::from("nvim/lua/options.lua.hbs")
+        );
+        assert_eq!(
+            render[1].patina_file.target,
+            PathBuf::from("output/nvim/lua/options.lua")
+        );
+        assert_eq!(render[1].render_str, "vim.opt.number = true");
+    }
+
+    #[test]
+    fn test_render_patina_template_glob() {
+        let tmp_dir = TmpTestDir::new();
+        fs::create_dir_all(tmp_dir.get_file_path("scripts")).unwrap();
+        tmp_dir.write_file("scripts/a.sh.hbs", "echo {{ a }}");
+        tmp_dir.write_file("scripts/b.sh.hbs", "echo {{ b }}");
+        tmp_dir.write_file("scripts/README.md", "These are scripts");
+
+        let patina = Patina {
+            base_path: Some(tmp_dir.path.clone()),
+            name: String::from("template-glob"),
+            description: String::from("This is a patina with a template glob"),
+            vars: Some(json!({ "a": "aaa", "b": "bbb" })),
+            files: vec![PatinaFile::new("scripts/*.sh.hbs", "~/bin/")],
+            ..Default::default()
+        };
+
+        let render = render_patina(&patina, None);
+
+        assert!(render.is_ok());
+        let render = render.unwrap().files;
+        assert_eq!(render.len(), 2);
+
+        assert_eq!(
+            render[0].patina_file.template,
+            PathBuf::from("scripts/a.sh.hbs")
+        );
+        assert_eq!(render[0].patina_file.target, PathBuf::from("~/bin/a.sh"));
+        assert_eq!(render[0].render_str, "echo aaa");
+
+        assert_eq!(
+            render[1].patina_file.template,
+            PathBuf::from("scripts/b.sh.hbs")
+        );
+        assert_eq!(render[1].patina_file.target, PathBuf::from("~/bin/b.sh"));
+        assert_eq!(render[1].render_str, "echo bbb");
+    }
+
+    #[test]
+    fn test_render_patina_template_glob_no_matches() {
+        let tmp_dir = TmpTestDir::new();
+
+        let patina = Patina {
+            base_path: Some(tmp_dir.path.clone()),
+            name: String::from("template-glob"),
+            description: String::from("This is a patina with a template glob"),
+            files: vec![PatinaFile::new("scripts/*.sh", "~/bin/")],
+            ..Default::default()
+        };
+
+        let render = render_patina(&patina, None);
+
+        assert!(render.is_ok());
+        assert!(render.unwrap().files.is_empty());
+    }
+
+    #[test]
+    fn test_render_patina_template_glob_invalid_pattern() {
+        let patina = Patina {
+            name: String::from("template-glob"),
+            description: String::from("This is a patina with an invalid template glob"),
+            files: vec![PatinaFile::new("scripts/[*.sh", "~/bin/")],
+            ..Default::default()
+        };
+
+        let render = render_patina(&patina, None);
+
+        assert!(render.is_err());
+        assert!(render.unwrap_err().is_invalid_glob());
+    }
+
+    #[test]
+    fn test_render_patina_file_vars() {
+        let tmp_dir = TmpTestDir::new();
+        let template_path = tmp_dir.write_file(
+            "gitconfig.hbs",
+            "email = {{ me.email }}\nname = {{ me.name }}",
+        );
+
+        let mut work_file = PatinaFile::new(template_path.clone(), PathBuf::from("work"));
+        work_file.vars = Some(json!({ "me": { "email": "me@work.com" } }));
+        let home_file = PatinaFile::new(template_path, PathBuf::from("home"));
+
+        let patina = Patina {
+            name: String::from("file-vars"),
+            description: String::from("This is a patina with per-file vars"),
+            vars: Some(json!({
+                "me": {
+                    "name": "Patina User",
+                    "email": "me@home.com"
+                }
+            })),
+            files: vec![work_file, home_file],
+            ..Default::default()
+        };
+
+        let render = render_patina(&patina, None);
+
+        assert!(render.is_ok());
+        let render = render.unwrap().files;
+        assert_eq!(render.len(), 2);
+        assert_eq!(
+            render[0].render_str,
+            "email = me@work.com\nname = Patina User"
+        );
+        assert_eq!(
+            render[1].render_str,
+            "email = me@home.com\nname = Patina User"
+        );
+    }
+
+    #[test]
+    fn test_render_patina_when() {
+        let tmp_dir = TmpTestDir::new();
+        let template_path = tmp_dir.write_file("template.txt.hbs", "This is {{ os }}.");
+
+        let mut linux_file = PatinaFile::new(template_path.clone(), PathBuf::from("linux.txt"));
+        linux_file.when = Some(String::from("{{eq os 'linux'}}"));
+        let mut mac_file = PatinaFile::new(template_path.clone(), PathBuf::from("mac.txt"));
+        mac_file.when = Some(String::from("eq os 'macos'"));
+        let mut work_file = PatinaFile::new(template_path.clone(), PathBuf::from("work.txt"));
+        work_file.when = Some(String::from("work"));
+        let mut home_file = PatinaFile::new(template_path, PathBuf::from("home.txt"));
+        home_file.when = Some(String::from("not work"));
+
+        let patina = Patina {
+            name: String::from("when-patina"),
+            description: String::from("This is a patina with conditional files"),
+            vars: Some(json!({ "os": "linux", "work": true })),
+            files: vec![linux_file, mac_file, work_file, home_file],
+            ..Default::default()
+        };
+
+        let render = render_patina(&patina, None);
+
+        assert!(render.is_ok());
+        let render = render.unwrap();
+        assert_eq!(render.files.len(), 2);
+        assert_eq!(
+            render.files[0].patina_file.target,
+            PathBuf::from("linux.txt")
+        );
+        assert_eq!(
+            render.files[1].patina_file.target,
+            PathBuf::from("work.txt")
+        );
+        assert_eq!(render.skipped_files.len(), 2);
+        assert_eq!(render.skipped_files[0], &patina.files[1]);
+        assert_eq!(render.skipped_files[1], &patina.files[3]);
+    }
+
+    #[test]
+    fn test_render_patina_when_missing_variable() {
+        let tmp_dir = TmpTestDir::new();
+        let template_path = tmp_dir.write_file("template.txt.hbs", "This is work.");
+
+        let mut work_file = PatinaFile::new(template_path, PathBuf::from("work.txt"));
+        work_file.when = Some(String::from("work"));
+
+        let patina = Patina {
+            name: String::from("when-patina"),
+            description: String::from("This is a patina with conditional files"),
+            vars: Some(json!({})),
+            files: vec![work_file],
+            ..Default::default()
+        };
+
+        let render = render_patina(&patina, None);
+
+        assert!(render.is_ok());
+        let render = render.unwrap();
+        assert!(render.files.is_empty());
+        assert_eq!(render.skipped_files.len(), 1);
+    }
+
+    #[test]
+    fn test_render_patina_when_invalid_expression() {
+        let mut patina_file = PatinaFile::new("template.txt.hbs", "output.txt");
+        patina_file.when = Some(String::from("{{#if}}"));
+
+        let patina = Patina {
+            name: String::from("when-patina"),
+            description: String::from("This is a patina with an invalid condition"),
+            files: vec![patina_file],
+            ..Default::default()
+        };
+
+        let render = render_patina(&patina, None);
+
+        assert!(render.is_err());
+        assert!(render.unwrap_err().is_render_template());
+    }
+
+    #[test]
+    fn test_render_patina_templated_paths() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file("dark.toml.hbs", "theme = {{ theme }}");
+
+        let patina = Patina {
+            name: String::from("templated-paths"),
+            description: String::from("This is a patina with templated paths"),
+            base_path: Some(tmp_dir.path.clone()),
+            vars: Some(json!({ "theme": "dark", "xdg_config": "config" })),
+            files: vec![PatinaFile::new(
+                "{{ theme }}.toml.hbs",
+                "{{ xdg_config }}/alacritty/{{ theme }}.toml",
+            )],
+            ..Default::default()
+        };
+
+        let render = render_patina(&patina, None);
+
+        assert!(render.is_ok());
+        let render = render.unwrap().files;
+        assert_eq!(render.len(), 1);
+        assert_eq!(
+            render[0].patina_file.template,
+            PathBuf::from("dark.toml.hbs")
+        );
+        assert_eq!(
+            render[0].patina_file.target,
+            PathBuf::from("config/alacritty/dark.toml")
+        );
+        assert_eq!(render[0].render_str, "theme = dark");
+    }
+
+    #[test]
+    fn test_render_patina_templated_paths_missing_variable() {
+        let patina = Patina {
+            name: String::from("templated-paths"),
+            description: String::from("This is a patina with templated paths"),
+            vars: Some(json!({})),
+            files: vec![PatinaFile::new(
+                "template.txt.hbs",
+                "{{ xdg_config }}/a.txt",
+            )],
+            ..Default::default()
+        };
+
+        let render = render_patina(&patina, None);
+
+        assert!(render.is_err());
+        assert!(render.unwrap_err().is_render_template());
+    }
+
+    #[test]
+    fn test_render_patina_templated_paths_unsafe() {
+        let cases = [
+            (
+                "{{ dir }}",
+                json!({ "dir": "" }),
+                "renders to an empty path",
+            ),
+            ("{{ dir }}", json!({ "dir": "/" }), "renders to a root path"),
+            (
+                "config/{{ dir }}/a.txt",
+                json!({ "dir": "../.." }),
+                "renders to a path that escapes its directory with `..`",
+            ),
+        ];
+
+        for (target, vars, message) in cases {
+            let patina = Patina {
+                name: String::from("templated-paths"),
+                description: String::from("This is a patina with templated paths"),
+                vars: Some(vars),
+                files: vec![PatinaFile::new("template.txt.hbs", target)],
+                ..Default::default()
+            };
+
+            let render = render_patina(&patina, None);
+
+            assert!(render.is_err());
+            assert_eq!(
+                render.unwrap_err().as_unsafe_path(),
+                Some((&PathBuf::from(target), &message.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_render_patina_facts() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file("os.txt.hbs", "{{ facts.os }}/{{ facts.arch }} {{ name }}");
+
+        let mut os_file = PatinaFile::new("os.txt.hbs", "{{ facts.os }}/os.txt");
+        os_file.when = Some(String::from("eq facts.os facts.os"));
+        let patina = Patina {
+            base_path: Some(tmp_dir.path.clone()),
+            name: String::from("facts"),
+            description: String::from("This is a patina that uses facts"),
+            vars: Some(json!({ "name": "Patina", "facts": { "os": "overridden" } })),
+            files: vec![os_file],
+            ..Default::default()
+        };
+
+        let render = render_patina(&patina, None);
+
+        assert!(render.is_ok());
+        let render = render.unwrap().files;
+        assert_eq!(render.len(), 1);
+        assert_eq!(
+            render[0].render_str,
+            format!("{}/{} Patina", std::env::consts::OS, std::env::consts::ARCH)
+        );
+        assert_eq!(
+            render[0].patina_file.target,
+            PathBuf::from(format!("{}/os.txt", std::env::consts::OS))
+        );
+    }
+
+    #[test]
+    fn test_render_patina_raw_files() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file("config.tmpl", "name: {{ .Name }}");
+        tmp_dir.write_file("vendored.hbs", "{{> partial }}");
+        tmp_dir.write_file("rendered.txt", "name: {{ name }}");
+
+        let mut vendored_file = PatinaFile::new("vendored.hbs", "vendored.txt");
+        vendored_file.render = Some(false);
+        let mut rendered_file = PatinaFile::new("rendered.txt", "rendered.txt");
+        rendered_file.render = Some(true);
+
+        let patina = Patina {
+            base_path: Some(tmp_dir.path.clone()),
+            name: String::from("raw-files"),
+            description: String::from("This is a patina with raw files"),
+            vars: Some(json!({ "name": "Patina" })),
+            files: vec![
+                PatinaFile::new("config.tmpl", "config.tmpl"),
+                vendored_file,
+                rendered_file,
+            ],
+            ..Default::default()
+        };
+
+        let render = render_patina(&patina, None);
+
+        assert!(render.is_ok());
+        let render = render.unwrap().files;
+        assert_eq!(render.len(), 3);
+        assert_eq!(render[0].render_str, "name: {{ .Name }}");
+        assert_eq!(render[1].render_str, "{{> partial }}");
+        assert_eq!(render[2].render_str, "name: Patina");
+    }
+
+    #[test]
+    fn test_render_patina_binary_file() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file("font.ttf.hbs", [0x00, 0x9f, b'{', b'{']);
+
+        let patina = Patina {
+            base_path: Some(tmp_dir.path.clone()),
+            name: String::from("binary-file"),
+            description: String::from("This is a patina with a binary file"),
+            files: vec![PatinaFile::new("font.ttf.hbs", "font.ttf")],
+            ..Default::default()
+        };
+
+        let render = render_patina(&patina, None);
+
+        assert!(render.is_ok());
+        let render = render.unwrap().files;
+        assert_eq!(render.len(), 1);
+        assert_eq!(render[0].render_str, "");
+        assert_eq!(render[0].render_bytes, Some(vec![0x00, 0x9f, b'{', b'{']));
+        assert_eq!(render[0].contents(), &[0x00, 0x9f, b'{', b'{']);
+    }
 }
@@ -0,0 +1,119 @@
+//! System facts, like the OS and hostname, that are available to templates as `facts`.
+//! Facts are gathered once, the first time they are used.
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+use std::sync::OnceLock;
+
+use serde_json::{json, Value};
+
+/// Get the system facts.
+/// Facts that can't be found on this system are empty strings, so that templates can check them with `#if`.
+pub fn get_facts() -> &'static Value {
+    static FACTS: OnceLock<Value> = OnceLock::new();
+    FACTS.get_or_init(gather_facts)
+}
+
+/// Gather the system facts
+fn gather_facts() -> Value {
+    let home = dirs::home_dir().unwrap_or_default();
+
+    json!({
+        "hostname": whoami::fallible::hostname().unwrap_or_default(),
+        "os": env::consts::OS,
+        "family": env::consts::FAMILY,
+        "arch": env::consts::ARCH,
+        "distro": whoami::distro(),
+        "username": whoami::username(),
+        "home": path_string(&home),
+        "xdg": {
+            "config_home": xdg_dir("XDG_CONFIG_HOME", &home, ".config"),
+            "data_home": xdg_dir("XDG_DATA_HOME", &home, ".local/share"),
+            "state_home": xdg_dir("XDG_STATE_HOME", &home, ".local/state"),
+            "cache_home": xdg_dir("XDG_CACHE_HOME", &home, ".cache"),
+            "runtime_dir": env::var("XDG_RUNTIME_DIR").unwrap_or_default(),
+        },
+        "shell": shell(),
+        "kernel": kernel_version(),
+    })
+}
+
+/// Get an XDG base directory from its environment variable, or its default under the home directory.
+/// The defaults follow the XDG spec on every OS, since that is where most dotfiles expect them.
+fn xdg_dir(var: &str, home: &Path, default: &str) -> String {
+    match env::var(var) {
+        Ok(dir) if Path::new(&dir).is_absolute() => dir,
+        _ => path_string(&home.join(default)),
+    }
+}
+
+/// Get the user's login shell
+fn shell() -> String {
+    env::var("SHELL")
+        .or_else(|_| env::var("COMSPEC"))
+        .unwrap_or_default()
+}
+
+/// Get the version of the OS kernel
+#[cfg(unix)]
+fn kernel_version() -> String {
+    match Command::new("uname").arg("-r").output() {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        }
+        _ => String::new(),
+    }
+}
+
+/// Get the version of the OS kernel
+#[cfg(windows)]
+fn kernel_version() -> String {
+    match Command::new("cmd").args(["/C", "ver"]).output() {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        }
+        _ => String::new(),
+    }
+}
+
+/// Convert a path to a string for templates
+fn path_string(path: &Path) -> String {
+    path.display().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_facts() {
+        let facts = get_facts();
+
+        assert_eq!(facts["os"], env::consts::OS);
+        assert_eq!(facts["arch"], env::consts::ARCH);
+        assert_eq!(facts["family"], env::consts::FAMILY);
+        for key in ["hostname", "distro", "username", "home", "shell", "kernel"] {
+            assert!(facts[key].is_string(), "{}", key);
+        }
+        for key in [
+            "config_home",
+            "data_home",
+            "state_home",
+            "cache_home",
+            "runtime_dir",
+        ] {
+            assert!(facts["xdg"][key].is_string(), "xdg.{}", key);
+        }
+    }
+
+    #[test]
+    fn test_xdg_dir() {
+        let home = Path::new("/home/me");
+
+        assert_eq!(
+            xdg_dir("DOTPATINA_TEST_UNSET_XDG_DIR", home, ".config"),
+            "/home/me/.config"
+        );
+    }
+}
@@ -18,7 +18,7 @@ impl TmpTestDir {
     }
 
     /// Write a file to the temporary directory and return the full PathBuf
-    pub fn write_file(&self, file_name: &str, contents: &str) -> PathBuf {
+    pub fn write_file<C: AsRef<[u8]>>(&self, file_name: &str, contents: C) -> PathBuf {
         let full_path = self.path.join(file_name);
         fs::write(&full_path, contents).unwrap();
         full_path
@@ -1,8 +1,9 @@
 //! Miscellaneous utilities used throughout the crate
 
 use std::{
-    fs,
+    fmt, fs,
     path::{Path, PathBuf},
+    process::Command,
 };
 
 use enum_as_inner::EnumAsInner;
@@ -24,47 +25,369 @@ pub enum Error {
     GetUserInput(std::io::Error),
 
     /// An error that occurs when parsing Toml data
-    TomlParse(toml::de::Error),
+    TomlParse(PathBuf, Option<Location>, Box<toml::de::Error>),
+
+    /// An error that occurs when parsing Json data
+    JsonParse(PathBuf, Option<Location>, serde_json::Error),
+
+    /// An error that occurs when parsing Yaml data
+    YamlParse(PathBuf, Option<Location>, serde_yaml_ng::Error),
+
+    /// Parsed patina data doesn't match the patina file schema
+    PatinaData(PathBuf, serde_json::Error),
+
+    /// A patina file has a schema version that this version of dotpatina can't read, with the path and version
+    UnsupportedVersion(PathBuf, u32),
+
+    /// An encrypted file could not be decrypted
+    Decrypt(PathBuf, Box<age::DecryptError>),
+
+    /// A file could not be encrypted
+    Encrypt(PathBuf, Box<age::EncryptError>),
+
+    /// An edited vars file is not valid data in its format, with the path the edited file was kept at
+    InvalidEdit(PathBuf, Box<Error>),
+
+    /// An encrypted vars file was given without an identity file or passphrase to decrypt it
+    MissingSecretKey(PathBuf),
+
+    /// A patina includes itself, directly or through other included patinas
+    IncludeCycle(PathBuf),
 
     /// An error that occurs when rendering a handlebars template
     RenderTemplate(handlebars::RenderError),
 
+    /// A template path is not a valid glob pattern
+    InvalidGlob(PathBuf, glob::PatternError),
+
+    /// An error that occurs when serializing data to a file format, with the path of the file and the error message
+    SerializeData(PathBuf, String),
+
+    /// A templated template or target path rendered to an empty or unsafe path,
+    /// with the unrendered path and a description of the problem
+    UnsafePath(PathBuf, String),
+
+    /// A managed block in a target file has missing or mismatched markers
+    InvalidBlock(PathBuf, String),
+
     /// A vars object is invalid
     InvalidVars(),
 
+    /// A profile does not exist in the patina
+    UnknownProfile(String),
+
+    /// A tag expression could not be parsed, with the expression and a description of the problem
+    InvalidTagExpression(String, String),
+
+    /// A `--set` variable could not be parsed, with the argument and a description of the problem
+    InvalidSetVar(String, String),
+
+    /// A table of vars with `+` keys is not a valid array directive, with where it is and a description of the problem
+    InvalidArrayDirective(String, String),
+
+    /// A `vars_exec` command could not be run or exited with a non-zero status, with the command and its stderr
+    VarsExecFailed(String, String),
+
+    /// A file in a patina uses options that can't be used together, with the patina path, the template, and the problem
+    InvalidPatinaFile(PathBuf, PathBuf, String),
+
+    /// Validation found problems in a patina
+    InvalidPatina(usize),
+
+    /// A hook command could not be started
+    RunHook(String, std::io::Error),
+
+    /// A hook command exited with a non-zero status, with the command and its exit code if it has one
+    HookFailed(String, Option<i32>),
+
     /// Failed to trash a file
     MoveFileToTrash(trash::Error),
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Message(message) => write!(f, "{}", message),
+            Error::FileRead(path, e) => write!(f, "{}: failed to read file: {}", path.display(), e),
+            Error::FileWrite(path, e) => {
+                write!(f, "{}: failed to write file: {}", path.display(), e)
+            }
+            Error::GetUserInput(e) => write!(f, "failed to get input: {}", e),
+            Error::TomlParse(path, Some(location), e) => write!(
+                f,
+                "{}:{}:{}: {}",
+                path.display(),
+                location.line,
+                location.column,
+                e.message()
+            ),
+            Error::TomlParse(path, None, e) => write!(f, "{}: {}", path.display(), e.message()),
+            Error::JsonParse(path, _, e) => write!(f, "{}: {}", path.display(), e),
+            Error::YamlParse(path, _, e) => write!(f, "{}: {}", path.display(), e),
+            Error::PatinaData(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::UnsupportedVersion(path, version) => write!(
+                f,
+                "{}: unsupported patina version {}",
+                path.display(),
+                version
+            ),
+            Error::Decrypt(path, e) => write!(f, "{}: failed to decrypt: {}", path.display(), e),
+            Error::Encrypt(path, e) => write!(f, "{}: failed to encrypt: {}", path.display(), e),
+            Error::InvalidEdit(path, e) => {
+                write!(f, "{}\nthe edited file was kept at {}", e, path.display())
+            }
+            Error::MissingSecretKey(path) => write!(
+                f,
+                "{}: an identity file or passphrase is needed to decrypt this file",
+                path.display()
+            ),
+            Error::IncludeCycle(path) => write!(f, "{}: patina includes itself", path.display()),
+            Error::RenderTemplate(e) => write!(f, "{}", e),
+            Error::InvalidGlob(path, e) => write!(f, "{}: invalid glob: {}", path.display(), e),
+            Error::SerializeData(path, message) => write!(
+                f,
+                "{}: failed to serialize data: {}",
+                path.display(),
+                message
+            ),
+            Error::UnsafePath(path, message)
+            | Error::InvalidBlock(path, message)
+            | Error::InvalidPatinaFile(_, path, message) => {
+                write!(f, "{}: {}", path.display(), message)
+            }
+            Error::InvalidVars() => write!(f, "invalid vars"),
+            Error::UnknownProfile(name) => write!(f, "unknown profile `{}`", name),
+            Error::InvalidTagExpression(expression, message) => {
+                write!(f, "invalid tag expression `{}`: {}", expression, message)
+            }
+            Error::InvalidSetVar(set_var, message) => {
+                write!(f, "invalid --set `{}`: {}", set_var, message)
+            }
+            Error::InvalidArrayDirective(location, message) => {
+                write!(f, "{}: invalid array directive: {}", location, message)
+            }
+            Error::VarsExecFailed(command, stderr) => {
+                write!(f, "vars_exec command `{}` failed: {}", command, stderr)
+            }
+            Error::InvalidPatina(count) => write!(f, "found {} problems in the patina", count),
+            Error::RunHook(command, e) => write!(f, "failed to run hook `{}`: {}", command, e),
+            Error::HookFailed(command, Some(code)) => {
+                write!(f, "hook `{}` failed with exit code {}", command, code)
+            }
+            Error::HookFailed(command, None) => write!(f, "hook `{}` failed", command),
+            Error::MoveFileToTrash(e) => write!(f, "failed to move file to trash: {}", e),
+        }
+    }
+}
+
 /// A Result type that uses the [`Error`] enum
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// A location within a text file, with 1-based line and column numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    /// The line number
+    pub line: usize,
+
+    /// The column number
+    pub column: usize,
+}
+
+impl Location {
+    /// Get the location of a byte offset within some text
+    pub fn from_offset(text: &str, offset: usize) -> Location {
+        let before = &text[..offset.min(text.len())];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let column = before[line_start..].chars().count() + 1;
+        Location { line, column }
+    }
+}
+
 /// Given a path, normalize it to an absolute path with cwd (`.`), home (`~`), and environment variables resolved.
+/// Links in the path are followed.
 pub fn normalize_path<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
-    let path = path.as_ref();
+    let path = expand_path(path.as_ref())?;
 
-    // resolve home dir and environment variables
+    // get the canonical path
+    match fs::canonicalize(&path) {
+        Ok(path) => Some(path),
+        Err(_) => Some(path),
+    }
+}
+
+/// Normalize a target path like [normalize_path], without following a link at the path itself.
+/// A target that is a link is replaced or removed, rather than the file it points to.
+pub fn normalize_target_path<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
+    let path = expand_path(path.as_ref())?;
+
+    // get the canonical path of the parent directory
+    if fs::symlink_metadata(&path).is_err() {
+        return Some(path);
+    }
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    match (fs::canonicalize(parent), path.file_name()) {
+        (Ok(parent), Some(file_name)) => Some(parent.join(file_name)),
+        _ => match fs::canonicalize(&path) {
+            Ok(path) => Some(path),
+            Err(_) => Some(path),
+        },
+    }
+}
+
+/// Resolve the home dir and environment variables in a path, and clean it by resolving `.` and multiple `/`s
+fn expand_path(path: &Path) -> Option<PathBuf> {
     let path = path.to_str()?;
     let path = match shellexpand::full(path) {
         Ok(path) => path.into_owned(),
         Err(_) => return None,
     };
+    Some(path_clean::clean(path))
+}
 
-    // clean it by resolving `.` and multiple `/`s
-    let path = path_clean::clean(path);
+/// Get the permission bits of a file, if it exists
+#[cfg(unix)]
+pub fn get_file_mode<P: AsRef<Path>>(path: P) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
 
-    // get the canonical path
-    match fs::canonicalize(&path) {
-        Ok(path) => Some(path),
-        Err(_) => Some(path),
+    let metadata = fs::metadata(path).ok()?;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+/// Get the permission bits of a file, if it exists
+#[cfg(not(unix))]
+pub fn get_file_mode<P: AsRef<Path>>(_path: P) -> Option<u32> {
+    None
+}
+
+/// Set the permission bits of a file
+#[cfg(unix)]
+pub fn set_file_mode<P: AsRef<Path>>(path: P, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+/// Set the permission bits of a file. File modes are only supported on unix.
+#[cfg(not(unix))]
+pub fn set_file_mode<P: AsRef<Path>>(_path: P, _mode: u32) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Write a file with the given permission bits.
+/// The contents are written to a private file next to the target and moved into place once its mode is set,
+/// so the contents are never readable with other permissions. Links to the target are followed.
+#[cfg(unix)]
+pub fn write_file_with_mode<P: AsRef<Path>>(
+    path: P,
+    contents: &[u8],
+    mode: u32,
+) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let path = fs::canonicalize(&path).unwrap_or(path.as_ref().to_path_buf());
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = path.with_file_name(format!(".{}.{}.tmp", file_name, uuid::Uuid::new_v4()));
+
+    let result = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .and_then(|mut file| file.write_all(contents))
+        .and_then(|_| set_file_mode(&tmp_path, mode))
+        .and_then(|_| fs::rename(&tmp_path, &path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
     }
+    result
+}
+
+/// Write a file with the given permission bits. File modes are only supported on unix.
+#[cfg(not(unix))]
+pub fn write_file_with_mode<P: AsRef<Path>>(
+    path: P,
+    contents: &[u8],
+    _mode: u32,
+) -> std::io::Result<()> {
+    fs::write(path, contents)
+}
+
+/// Create a symbolic link at `link` that points to `original`
+#[cfg(unix)]
+pub fn create_symlink<P: AsRef<Path>, Q: AsRef<Path>>(original: P, link: Q) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+/// Create a symbolic link at `link` that points to `original`
+#[cfg(windows)]
+pub fn create_symlink<P: AsRef<Path>, Q: AsRef<Path>>(original: P, link: Q) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(original, link)
+}
+
+/// Create a [Command] that runs a command string with the system shell
+#[cfg(unix)]
+pub fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+/// Create a [Command] that runs a command string with the system shell
+#[cfg(windows)]
+pub fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// Determine whether file contents are binary, rather than text.
+/// Contents are binary if they are not valid UTF-8 or contain a NUL byte.
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.contains(&0) || std::str::from_utf8(bytes).is_err()
+}
+
+/// Recursively list all files in a directory, as paths relative to that directory in sorted order.
+pub fn list_files_in_dir<P: AsRef<Path>>(dir: P) -> Result<Vec<PathBuf>> {
+    fn collect_files(dir: &Path, relative_dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => return Err(Error::FileRead(dir.to_path_buf(), e)),
+        };
+
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => return Err(Error::FileRead(dir.to_path_buf(), e)),
+            };
+            let path = entry.path();
+            let relative_path = relative_dir.join(entry.file_name());
+            if path.is_dir() {
+                collect_files(&path, &relative_path, files)?;
+            } else {
+                files.push(relative_path);
+            }
+        }
+
+        Ok(())
+    }
+
+    let mut files = vec![];
+    collect_files(dir.as_ref(), Path::new(""), &mut files)?;
+    files.sort();
+    Ok(files)
 }
 
 #[cfg(test)]
 pub mod tests {
-    use std::path::PathBuf;
+    use std::{fs, path::PathBuf};
 
-    use super::normalize_path;
+    use super::{is_binary, list_files_in_dir, normalize_path, Location};
+    use crate::tests::test_utils::TmpTestDir;
 
     pub fn get_home_dir() -> String {
         let home_dir = dirs::home_dir().unwrap();
@@ -107,4 +430,118 @@ pub mod tests {
             path.unwrap()
         );
     }
+
+    #[test]
+    fn test_location_from_offset() {
+        let text = "aaa\nbbb\nccc";
+        assert_eq!(
+            Location::from_offset(text, 0),
+            Location { line: 1, column: 1 }
+        );
+        assert_eq!(
+            Location::from_offset(text, 5),
+            Location { line: 2, column: 2 }
+        );
+        assert_eq!(
+            Location::from_offset(text, 100),
+            Location { line: 3, column: 4 }
+        );
+    }
+
+    #[test]
+    fn test_list_files_in_dir() {
+        let tmp_dir = TmpTestDir::new();
+        fs::create_dir_all(tmp_dir.get_file_path("lua/plugins")).unwrap();
+        tmp_dir.write_file("init.lua", "");
+        tmp_dir.write_file("lua/options.lua", "");
+        tmp_dir.write_file("lua/plugins/telescope.lua", "");
+
+        let files = list_files_in_dir(&tmp_dir.path);
+
+        assert!(files.is_ok());
+        assert_eq!(
+            files.unwrap(),
+            vec![
+                PathBuf::from("init.lua"),
+                PathBuf::from("lua/options.lua"),
+                PathBuf::from("lua/plugins/telescope.lua"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_list_files_in_dir_missing_dir() {
+        let files = list_files_in_dir("this/dir/does/not/exist");
+        assert!(files.is_err());
+        assert!(files.unwrap_err().is_file_read());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_normalize_path_follows_link() {
+        let tmp_dir = TmpTestDir::new();
+        let file_path = tmp_dir.write_file("file.txt", "");
+        let link_path = tmp_dir.get_file_path("link.txt");
+        super::create_symlink(&file_path, &link_path).unwrap();
+
+        let path = normalize_path(&link_path);
+        assert_eq!(normalize_path(&file_path).unwrap(), path.unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_normalize_target_path_does_not_follow_link() {
+        let tmp_dir = TmpTestDir::new();
+        let file_path = tmp_dir.write_file("file.txt", "");
+        let link_path = tmp_dir.get_file_path("link.txt");
+        super::create_symlink(&file_path, &link_path).unwrap();
+
+        let path = super::normalize_target_path(&link_path);
+        assert_eq!(
+            normalize_path(&tmp_dir.path).unwrap().join("link.txt"),
+            path.unwrap()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_file_with_mode() {
+        let tmp_dir = TmpTestDir::new();
+        let new_path = tmp_dir.get_file_path("new.txt");
+        let existing_path = tmp_dir.write_file("existing.txt", "old");
+        super::set_file_mode(&existing_path, 0o644).unwrap();
+
+        super::write_file_with_mode(&new_path, b"new", 0o755).unwrap();
+        super::write_file_with_mode(&existing_path, b"private", 0o600).unwrap();
+
+        assert_eq!(fs::read_to_string(&new_path).unwrap(), "new");
+        assert_eq!(super::get_file_mode(&new_path), Some(0o755));
+        assert_eq!(fs::read_to_string(&existing_path).unwrap(), "private");
+        assert_eq!(super::get_file_mode(&existing_path), Some(0o600));
+        assert_eq!(fs::read_dir(&tmp_dir.path).unwrap().count(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_file_with_mode_follows_link() {
+        let tmp_dir = TmpTestDir::new();
+        let file_path = tmp_dir.write_file("file.txt", "old");
+        let link_path = tmp_dir.get_file_path("link.txt");
+        super::create_symlink(&file_path, &link_path).unwrap();
+
+        super::write_file_with_mode(&link_path, b"new", 0o600).unwrap();
+
+        assert!(link_path.is_symlink());
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "new");
+        assert_eq!(super::get_file_mode(&file_path), Some(0o600));
+    }
+
+    #[test]
+    fn test_is_binary() {
+        assert!(!is_binary(b""));
+        assert!(!is_binary("Hello, {{ name }}! ✓".as_bytes()));
+        assert!(is_binary(&[0x00, 0x9f, 0x92, 0x96]));
+        assert!(is_binary(b"text with a \0 byte"));
+        assert!(is_binary(&[0xff, 0xfe]));
+    }
 }
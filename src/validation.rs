@@ -0,0 +1,628 @@
+//! The validation module statically checks a [Patina] for problems, without writing to any target files.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use handlebars::{RenderErrorReason, Template};
+use serde_json::Value;
+
+use crate::block;
+use crate::patina::format;
+use crate::patina::patina_file::PatinaFile;
+use crate::patina::tags::TagExpression;
+use crate::patina::Patina;
+use crate::templating;
+use crate::utils::{is_binary, normalize_path, Error};
+
+/// A [ValidationIssue] is a single problem found in a [Patina]
+#[derive(Debug, PartialEq)]
+pub struct ValidationIssue {
+    /// The path of the file that has the problem
+    pub path: PathBuf,
+
+    /// A description of the problem
+    pub message: String,
+}
+
+impl ValidationIssue {
+    pub fn new<P: AsRef<Path>, S: Into<String>>(path: P, message: S) -> ValidationIssue {
+        ValidationIssue {
+            path: path.as_ref().to_path_buf(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.message)
+    }
+}
+
+/// Find keys that are not recognized in a patina file and the patina files it includes.
+/// Files that can't be read or parsed are skipped, since loading the patina reports those problems.
+pub fn find_unknown_keys(patina_path: &Path) -> Vec<ValidationIssue> {
+    fn find(path: &Path, visited: &mut Vec<PathBuf>, issues: &mut Vec<ValidationIssue>) {
+        let normalized_path = normalize_path(path).unwrap_or(path.to_path_buf());
+        if visited.contains(&normalized_path) {
+            return;
+        }
+        visited.push(normalized_path);
+
+        let Ok((mut patina, unknown_keys)) =
+            format::read_data_file_with_unknown_keys::<Patina>(path)
+        else {
+            return;
+        };
+        for key in unknown_keys {
+            issues.push(ValidationIssue::new(path, format!("unknown key `{}`", key)));
+        }
+
+        patina.base_path = path.parent().map(Path::to_path_buf);
+        for include in &patina.include {
+            find(&patina.get_patina_path(include), visited, issues);
+        }
+    }
+
+    let mut issues = vec![];
+    find(patina_path, &mut vec![], &mut issues);
+    issues
+}
+
+/// Check the files of a [Patina] for problems:
+/// - templates that are missing or fail to parse
+/// - templates that reference undefined variables
+/// - managed blocks with missing or mismatched markers in their targets
+/// - targets that are used by multiple files, or that are inside another target,
+///   except for managed blocks with different names
+/// - absent targets that are also the target of a file
+pub fn validate_patina(patina: &Patina, tags: Option<TagExpression>) -> Vec<ValidationIssue> {
+    let hb = templating::new_handlebars();
+    let mut issues = vec![];
+    let mut targets: Vec<(PathBuf, PathBuf, Option<String>)> = vec![];
+
+    for pf in patina.files_for_tags(tags.clone()) {
+        // Files that are skipped on this machine still need to exist and parse
+        let context = templating::render_context(patina, pf);
+        let when = match templating::evaluate_when(&hb, &context, pf) {
+            Ok(when) => when,
+            Err(e) => {
+                let message = format!("invalid when condition: {}", e);
+                issues.push(ValidationIssue::new(&pf.template, message));
+                continue;
+            }
+        };
+
+        let patina_files = match templating::expand_patina_file(&hb, patina, pf, &context) {
+            Ok(patina_files) if patina_files.is_empty() && pf.is_template_glob() => {
+                issues.push(ValidationIssue::new(
+                    &pf.template,
+                    "template glob matches no files",
+                ));
+                continue;
+            }
+            Ok(patina_files) => patina_files,
+            Err(e) => {
+                let message = format!("failed to expand template: {}", e);
+                issues.push(ValidationIssue::new(&pf.template, message));
+                continue;
+            }
+        };
+
+        for pf in patina_files {
+            let context = when.then_some(&context);
+            if let Some(issue) = validate_patina_file(&hb, patina, &pf, context) {
+                issues.push(issue);
+            }
+            if when {
+                targets.push((
+                    patina.get_target_path(&pf),
+                    pf.template.clone(),
+                    pf.block_name(),
+                ));
+            }
+        }
+    }
+
+    for (i, (target, template, block_name)) in targets.iter().enumerate() {
+        for (other_target, other_template, other_block_name) in targets.iter().skip(i + 1) {
+            // Managed blocks with different names can share a target
+            let blocks = block_name.as_ref().zip(other_block_name.as_ref());
+            if target == other_target && blocks.is_some_and(|(a, b)| a != b) {
+                continue;
+            }
+
+            if target == other_target && blocks.is_some() {
+                let message = format!(
+                    "managed block `{}` is used by multiple templates: {}, {}",
+                    block_name.as_deref().unwrap_or_default(),
+                    template.display(),
+                    other_template.display()
+                );
+                issues.push(ValidationIssue::new(target, message));
+            } else if target == other_target {
+                let message = format!(
+                    "target is used by multiple templates: {}, {}",
+                    template.display(),
+                    other_template.display()
+                );
+                issues.push(ValidationIssue::new(target, message));
+            } else if other_target.starts_with(target) || target.starts_with(other_target) {
+                // Report the collision on the target that contains the other one
+                let (outer, outer_template, inner, inner_template) =
+                    match other_target.starts_with(target) {
+                        true => (target, template, other_target, other_template),
+                        false => (other_target, other_template, target, template),
+                    };
+                let message = format!(
+                    "target of {} contains the target of {}: {}",
+                    outer_template.display(),
+                    inner_template.display(),
+                    inner.display()
+                );
+                issues.push(ValidationIssue::new(outer, message));
+            }
+        }
+    }
+
+    for absent_file in patina.absent_for_tags(tags) {
+        let target = patina.get_absent_target_path(absent_file);
+        if let Some((_, template, _)) = targets.iter().find(|(t, _, _)| *t == target) {
+            let message = format!("absent target is also the target of {}", template.display());
+            issues.push(ValidationIssue::new(&target, message));
+        }
+    }
+
+    issues
+}
+
+/// Find vars named `facts`, which are replaced by the system facts when rendering templates.
+/// Vars of the patina are reported on the patina file, and vars of a file on its template.
+pub fn find_facts_vars(patina_path: &Path, patina: &Patina) -> Vec<ValidationIssue> {
+    let has_facts = |vars: &Option<Value>| vars.as_ref().is_some_and(|v| v.get("facts").is_some());
+    let message = "var `facts` is replaced by the system facts in templates";
+
+    let mut issues = vec![];
+    if has_facts(&patina.vars) {
+        issues.push(ValidationIssue::new(patina_path, message));
+    }
+    for pf in patina.files.iter().filter(|pf| has_facts(&pf.vars)) {
+        issues.push(ValidationIssue::new(&pf.template, message));
+    }
+    issues
+}
+
+/// Check a single [PatinaFile] template for problems.
+/// The template is only rendered if it has a render context, since its vars may not be defined otherwise.
+fn validate_patina_file(
+    hb: &handlebars::Handlebars,
+    patina: &Patina,
+    patina_file: &PatinaFile,
+    context: Option<&Value>,
+) -> Option<ValidationIssue> {
+    let template_path = patina.get_template_path(patina_file);
+    let template_bytes = match fs::read(&template_path) {
+        Ok(template_bytes) => template_bytes,
+        Err(e) => {
+            let message = format!("failed to read template: {}", e);
+            return Some(ValidationIssue::new(&patina_file.template, message));
+        }
+    };
+
+    if let Some(message) = patina_file.check_options() {
+        return Some(ValidationIssue::new(&patina_file.template, message));
+    }
+
+    if let Some(block_name) = patina_file.block_name() {
+        let target_path = patina.get_target_path(patina_file);
+        let target_str = fs::read_to_string(&target_path).unwrap_or_default();
+        if let Err(Error::InvalidBlock(_, message)) =
+            block::get_block(&target_path, &target_str, &block_name)
+        {
+            return Some(ValidationIssue::new(&target_path, message));
+        }
+    }
+
+    if is_binary(&template_bytes) {
+        return match patina_file.merge {
+            Some(_) => {
+                let message = "a binary file can't be merged into its target";
+                Some(ValidationIssue::new(&patina_file.template, message))
+            }
+            None => None,
+        };
+    }
+    let template_str = String::from_utf8_lossy(&template_bytes);
+
+    // Raw and linked files are not templated
+    if !patina_file.is_rendered() {
+        return validate_merge_data(patina_file, &template_str);
+    }
+
+    if let Err(e) = Template::compile(&template_str) {
+        let message = format!("failed to parse template: {}", e.reason());
+        return Some(ValidationIssue::new(&patina_file.template, message));
+    }
+
+    let context = context?;
+    match hb.render_template(&template_str, context) {
+        Ok(render_str) => validate_merge_data(patina_file, &render_str),
+        Err(e) => {
+            let message = match e.reason() {
+                RenderErrorReason::MissingVariable(Some(name)) => {
+                    format!("undefined variable `{}`", name)
+                }
+                reason => format!("failed to render template: {}", reason),
+            };
+            Some(ValidationIssue::new(&patina_file.template, message))
+        }
+    }
+}
+
+/// Check that the contents of a merged [PatinaFile] parse in its merge format
+fn validate_merge_data(patina_file: &PatinaFile, contents: &str) -> Option<ValidationIssue> {
+    let format = patina_file.merge?;
+    let reason =
+        match format::parse_data_as::<serde_json::Value>(&patina_file.template, contents, format) {
+            Ok(_) => return None,
+            Err(Error::TomlParse(_, _, e)) => e.message().to_string(),
+            Err(Error::JsonParse(_, _, e)) => e.to_string(),
+            Err(Error::YamlParse(_, _, e)) => e.to_string(),
+            Err(e) => e.to_string(),
+        };
+    let message = format!("template is not valid {:?} for merging: {}", format, reason);
+    Some(ValidationIssue::new(&patina_file.template, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::patina::absent::AbsentFile;
+    use crate::patina::format::Format;
+    use crate::patina::patina_file::ManagedBlock;
+    use crate::tests::test_utils::TmpTestDir;
+
+    #[test]
+    fn test_find_unknown_keys() {
+        let tmp_dir = TmpTestDir::new();
+        let base_path = tmp_dir.write_file(
+            "base.toml",
+            r#"
+                name = "base"
+
+                [[files]]
+                template = "a.txt"
+                target = "a.txt"
+                mdoe = "0755"
+            "#,
+        );
+        let patina_path = tmp_dir.write_file(
+            "patina.toml",
+            r#"
+                name = "unknown-keys"
+                include = ["base.toml"]
+                descripton = "typo"
+            "#,
+        );
+
+        let issues = find_unknown_keys(&patina_path);
+
+        assert_eq!(
+            issues,
+            vec![
+                ValidationIssue::new(&patina_path, "unknown key `descripton`"),
+                ValidationIssue::new(&base_path, "unknown key `files.0.mdoe`"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_patina_valid() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file("a.txt.hbs", "Hello, {{ name }}!");
+
+        let patina = Patina {
+            base_path: Some(tmp_dir.path.clone()),
+            vars: Some(json!({ "name": "Patina" })),
+            files: vec![PatinaFile::new("a.txt.hbs", "output/a.txt")],
+            ..Default::default()
+        };
+
+        let issues = validate_patina(&patina, None);
+
+        assert!(issues.is_empty());
+    }
+
+    #[test]
+    fn test_validate_patina_template_problems() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file("invalid.hbs", "Hello, {{ name }!");
+        tmp_dir.write_file("undefined.hbs", "Hello, {{ me.name }}!");
+
+        let patina = Patina {
+            base_path: Some(tmp_dir.path.clone()),
+            vars: Some(json!({})),
+            files: vec![
+                PatinaFile::new("missing.hbs", "missing.txt"),
+                PatinaFile::new("invalid.hbs", "invalid.txt"),
+                PatinaFile::new("undefined.hbs", "undefined.txt"),
+            ],
+            ..Default::default()
+        };
+
+        let issues = validate_patina(&patina, None);
+
+        assert_eq!(issues.len(), 3);
+        assert_eq!(issues[0].path, PathBuf::from("missing.hbs"));
+        assert!(issues[0].message.starts_with("failed to read template"));
+        assert_eq!(issues[1].path, PathBuf::from("invalid.hbs"));
+        assert!(issues[1].message.starts_with("failed to parse template"));
+        assert_eq!(
+            issues[2],
+            ValidationIssue::new("undefined.hbs", "undefined variable `me.name`")
+        );
+    }
+
+    #[test]
+    fn test_validate_patina_facts() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file("os.hbs", "{{ facts.os }} {{ name }}");
+
+        let mut patina_file = PatinaFile::new("os.hbs", "{{ facts.os }}.txt");
+        patina_file.when = Some(String::from("facts.os"));
+        let patina = Patina {
+            base_path: Some(tmp_dir.path.clone()),
+            vars: Some(json!({ "name": "Patina" })),
+            files: vec![patina_file],
+            ..Default::default()
+        };
+
+        let issues = validate_patina(&patina, None);
+
+        assert!(issues.is_empty());
+    }
+
+    #[test]
+    fn test_validate_patina_glob_without_matches() {
+        let tmp_dir = TmpTestDir::new();
+        fs::create_dir_all(tmp_dir.get_file_path("scripts")).unwrap();
+        tmp_dir.write_file("scripts/a.sh", "echo a");
+
+        let patina = Patina {
+            base_path: Some(tmp_dir.path.clone()),
+            files: vec![
+                PatinaFile::new("scripts/*.sh", "bin/"),
+                PatinaFile::new("none/*.zz", "zz/"),
+            ],
+            ..Default::default()
+        };
+
+        let issues = validate_patina(&patina, None);
+
+        assert_eq!(
+            issues,
+            vec![ValidationIssue::new(
+                "none/*.zz",
+                "template glob matches no files"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_find_facts_vars() {
+        let mut facts_file = PatinaFile::new("facts.hbs", "facts.txt");
+        facts_file.vars = Some(json!({ "facts": { "os": "plan9" } }));
+        let patina = Patina {
+            vars: Some(json!({ "facts": "mine", "name": "Patina" })),
+            files: vec![PatinaFile::new("name.hbs", "name.txt"), facts_file],
+            ..Default::default()
+        };
+
+        let issues = find_facts_vars(Path::new("patina.toml"), &patina);
+
+        let message = "var `facts` is replaced by the system facts in templates";
+        assert_eq!(
+            issues,
+            vec![
+                ValidationIssue::new("patina.toml", message),
+                ValidationIssue::new("facts.hbs", message),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_patina_skipped_files_are_not_rendered() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file("work.hbs", "{{ work.email }}");
+
+        let mut patina_file = PatinaFile::new("work.hbs", "work.txt");
+        patina_file.when = Some(String::from("work"));
+        let patina = Patina {
+            base_path: Some(tmp_dir.path.clone()),
+            vars: Some(json!({})),
+            files: vec![patina_file],
+            ..Default::default()
+        };
+
+        let issues = validate_patina(&patina, None);
+
+        assert!(issues.is_empty());
+    }
+
+    #[test]
+    fn test_validate_patina_target_problems() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file("a.txt", "");
+        tmp_dir.write_file("b.txt", "");
+        tmp_dir.write_file("c.txt", "");
+
+        let patina = Patina {
+            base_path: Some(tmp_dir.path.clone()),
+            files: vec![
+                PatinaFile::new("a.txt", "output/a.txt"),
+                PatinaFile::new("b.txt", "output/a.txt"),
+                PatinaFile::new("c.txt", "output/a.txt/c.txt"),
+            ],
+            ..Default::default()
+        };
+
+        let issues = validate_patina(&patina, None);
+
+        let target = tmp_dir.path.join("output/a.txt");
+        assert_eq!(
+            issues,
+            vec![
+                ValidationIssue::new(
+                    &target,
+                    "target is used by multiple templates: a.txt, b.txt"
+                ),
+                ValidationIssue::new(
+                    &target,
+                    format!(
+                        "target of a.txt contains the target of c.txt: {}",
+                        target.join("c.txt").display()
+                    )
+                ),
+                ValidationIssue::new(
+                    &target,
+                    format!(
+                        "target of b.txt contains the target of c.txt: {}",
+                        target.join("c.txt").display()
+                    )
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_patina_absent_target_problems() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file("a.txt", "");
+
+        let patina = Patina {
+            base_path: Some(tmp_dir.path.clone()),
+            files: vec![PatinaFile::new("a.txt", "output/a.txt")],
+            absent: vec![
+                AbsentFile {
+                    target: PathBuf::from("output/a.txt"),
+                    ..Default::default()
+                },
+                AbsentFile {
+                    target: PathBuf::from("output/b.txt"),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        let issues = validate_patina(&patina, None);
+
+        assert_eq!(
+            issues,
+            vec![ValidationIssue::new(
+                tmp_dir.path.join("output/a.txt"),
+                "absent target is also the target of a.txt"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_validate_patina_block_problems() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file("bashrc", "export EDITOR=vim\n");
+        tmp_dir.write_file(".bashrc", "# END dotpatina shell\n");
+
+        let mut block_file = PatinaFile::new("bashrc", ".bashrc");
+        block_file.block = Some(ManagedBlock::Named(String::from("shell")));
+        let mut link_file = PatinaFile::new("bashrc", ".profile");
+        link_file.block = Some(ManagedBlock::Enabled(true));
+        link_file.link = true;
+        let patina = Patina {
+            name: String::from("shell"),
+            base_path: Some(tmp_dir.path.clone()),
+            files: vec![block_file, link_file],
+            ..Default::default()
+        };
+
+        let issues = validate_patina(&patina, None);
+
+        assert_eq!(
+            issues,
+            vec![
+                ValidationIssue::new(
+                    tmp_dir.path.join(".bashrc"),
+                    "found `# END dotpatina shell` without `# BEGIN dotpatina shell`"
+                ),
+                ValidationIssue::new("bashrc", "a linked file can't be a managed block"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_patina_shared_block_targets() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file("aliases", "alias ll=\"ls -l\"\n");
+        tmp_dir.write_file("exports", "export EDITOR=vim\n");
+        tmp_dir.write_file("more-aliases", "alias la=\"ls -a\"\n");
+
+        let mut aliases_file = PatinaFile::new("aliases", ".bashrc");
+        aliases_file.block = Some(ManagedBlock::Named(String::from("aliases")));
+        let mut exports_file = PatinaFile::new("exports", ".bashrc");
+        exports_file.block = Some(ManagedBlock::Enabled(true));
+        let mut more_aliases_file = PatinaFile::new("more-aliases", ".bashrc");
+        more_aliases_file.block = Some(ManagedBlock::Named(String::from("aliases")));
+        let patina = Patina {
+            base_path: Some(tmp_dir.path.clone()),
+            files: vec![aliases_file, exports_file, more_aliases_file],
+            ..Default::default()
+        };
+
+        let issues = validate_patina(&patina, None);
+
+        assert_eq!(
+            issues,
+            vec![ValidationIssue::new(
+                tmp_dir.path.join(".bashrc"),
+                "managed block `aliases` is used by multiple templates: aliases, more-aliases"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_validate_patina_merge_problems() {
+        let tmp_dir = TmpTestDir::new();
+        tmp_dir.write_file(
+            "settings.json.hbs",
+            "{ \"editor.fontSize\": {{ font_size }}, }",
+        );
+        tmp_dir.write_file("config.toml", "size = 14\n");
+
+        let mut json_file = PatinaFile::new("settings.json.hbs", "settings.json");
+        json_file.merge = Some(Format::Json);
+        let mut link_file = PatinaFile::new("config.toml", "config.toml");
+        link_file.merge = Some(Format::Toml);
+        link_file.link = true;
+        let patina = Patina {
+            base_path: Some(tmp_dir.path.clone()),
+            vars: Some(json!({ "font_size": 14 })),
+            files: vec![json_file, link_file],
+            ..Default::default()
+        };
+
+        let issues = validate_patina(&patina, None);
+
+        assert_eq!(
+            issues,
+            vec![
+                ValidationIssue::new(
+                    "settings.json.hbs",
+                    "template is not valid Json for merging: trailing comma at line 1 column 26"
+                ),
+                ValidationIssue::new("config.toml", "a merged file can't be linked"),
+            ]
+        );
+    }
+}